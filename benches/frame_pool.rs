@@ -0,0 +1,65 @@
+//! Compares the allocations made while decoding with and without the frame
+//! pool of `Dec::set_frame_pool`.
+//!
+//! Run with `cargo bench --bench frame_pool`.
+
+extern crate av_codec as codec;
+extern crate av_data as data;
+extern crate av_vorbis;
+
+// The fixture refers to them from the crate root
+use av_vorbis::{decoder, stream};
+use codec::decoder::Decoder;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+#[allow(dead_code)]
+#[path = "../src/fixture.rs"]
+mod fixture;
+
+/// Counts the allocations made through the global allocator.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const PACKETS: usize = 20_000;
+
+fn main() {
+    let packets = fixture::audio_packets(&[true; PACKETS], &[255, 200]);
+    let packets: Vec<_> = packets.iter().map(|data| fixture::packet(data)).collect();
+    for &pool in &[0, 4] {
+        let mut dec = decoder::Dec::new();
+        dec.set_frame_pool(pool);
+        let mut dec = fixture::configure(dec, 2);
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for pkt in &packets {
+            dec.send_packet(pkt).unwrap();
+            // Dropped right away, as a sink copying the samples would
+            while dec.receive_frame().is_ok() {}
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        println!(
+            "pool {}: {:.1} allocations per packet, {:.0} allocations/s, {:.0} packets/s",
+            pool,
+            allocations as f64 / PACKETS as f64,
+            allocations as f64 / elapsed,
+            PACKETS as f64 / elapsed,
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Reading of the Vorbis comment header and the pictures embedded in it.
+//!
+//! The comments are meant to be UTF-8, but files with other encodings are
+//! common. Rather than rejecting them or dropping entries silently, the
+//! vendor string and the comments are decoded lossily: invalid sequences
+//! become U+FFFD. Entries without a `=` separating the field name from the
+//! value are skipped, as they can not be represented as a pair.
+
+use lewton::header::CommentHeader;
+
+fn read_u32(data: &mut &[u8]) -> Option<u32> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (v, rest) = data.split_at(4);
+    *data = rest;
+    Some(u32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+}
+
+fn read_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_u32(data)? as usize;
+    if len > data.len() {
+        return None;
+    }
+    let (s, rest) = data.split_at(len);
+    *data = rest;
+    Some(s)
+}
+
+/// Reads a complete comment header packet, `None` if it is malformed.
+pub(crate) fn read_comment_header(packet: &[u8]) -> Option<CommentHeader> {
+    if packet.len() < 7 || packet[0] != 3 || &packet[1..7] != b"vorbis" {
+        return None;
+    }
+    let mut data = &packet[7..];
+    let vendor = String::from_utf8_lossy(read_string(&mut data)?).into_owned();
+    let count = read_u32(&mut data)?;
+    let mut comment_list = Vec::new();
+    for _ in 0..count {
+        let comment = read_string(&mut data)?;
+        if let Some(eq) = comment.iter().position(|&b| b == b'=') {
+            let key = String::from_utf8_lossy(&comment[..eq]).into_owned();
+            let value = String::from_utf8_lossy(&comment[eq + 1..]).into_owned();
+            comment_list.push((key, value));
+        }
+    }
+    if data.first().is_none_or(|&framing| framing & 1 == 0) {
+        return None;
+    }
+    Some(CommentHeader {
+        vendor,
+        comment_list,
+    })
+}
+
+/// An embedded picture, as stored base64 encoded in the
+/// `METADATA_BLOCK_PICTURE` comment using the FLAC picture block layout.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Picture {
+    /// The picture type of the ID3v2 APIC frame, 3 for the front cover.
+    pub picture_type: u32,
+    pub mime: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    /// Color depth in bits per pixel.
+    pub depth: u32,
+    /// Number of colors of indexed pictures, 0 otherwise.
+    pub colors: u32,
+    pub data: Vec<u8>,
+}
+
+/// Decodes standard base64 with padding, `None` on invalid input.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+    let s = s.trim().as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, quad) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let pad = quad.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return None;
+        }
+        let mut v = 0;
+        for &c in &quad[..4 - pad] {
+            v = v << 6 | value(c)?;
+        }
+        v <<= 6 * pad as u32;
+        let bytes = [(v >> 16) as u8, (v >> 8) as u8, v as u8];
+        out.extend_from_slice(&bytes[..3 - pad]);
+    }
+    Some(out)
+}
+
+fn read_u32_be(data: &mut &[u8]) -> Option<u32> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (v, rest) = data.split_at(4);
+    *data = rest;
+    Some(u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+}
+
+fn read_bytes_be<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_u32_be(data)? as usize;
+    if len > data.len() {
+        return None;
+    }
+    let (s, rest) = data.split_at(len);
+    *data = rest;
+    Some(s)
+}
+
+/// Parses the value of a `METADATA_BLOCK_PICTURE` comment, `None` if it
+/// is not a valid picture block.
+pub fn parse_picture(value: &str) -> Option<Picture> {
+    let block = decode_base64(value)?;
+    let mut data = block.as_slice();
+    let picture_type = read_u32_be(&mut data)?;
+    let mime = String::from_utf8(read_bytes_be(&mut data)?.to_vec()).ok()?;
+    let description = String::from_utf8_lossy(read_bytes_be(&mut data)?).into_owned();
+    let width = read_u32_be(&mut data)?;
+    let height = read_u32_be(&mut data)?;
+    let depth = read_u32_be(&mut data)?;
+    let colors = read_u32_be(&mut data)?;
+    let image = read_bytes_be(&mut data)?;
+    Some(Picture {
+        picture_type,
+        mime,
+        description,
+        width,
+        height,
+        depth,
+        colors,
+        data: image.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture;
+
+    #[test]
+    fn invalid_utf8_is_decoded_lossily() {
+        let packet = fixture::comment(
+            b"fix\xffture",
+            &[b"TITLE=caf\xc3", b"no separator", b"ARTIST=someone"],
+        );
+        let header = read_comment_header(&packet).unwrap();
+        assert_eq!(header.vendor, "fix\u{fffd}ture");
+        assert_eq!(
+            header.comment_list,
+            vec![
+                ("TITLE".to_string(), "caf\u{fffd}".to_string()),
+                ("ARTIST".to_string(), "someone".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_comment_header_is_rejected() {
+        let packet = fixture::comment(b"fixture", &[b"TITLE=x"]);
+        for len in 0..packet.len() {
+            assert!(read_comment_header(&packet[..len]).is_none());
+        }
+    }
+
+    #[test]
+    fn base64_handles_the_padding() {
+        assert_eq!(decode_base64("TWFu"), Some(b"Man".to_vec()));
+        assert_eq!(decode_base64("TWE="), Some(b"Ma".to_vec()));
+        assert_eq!(decode_base64("TQ=="), Some(b"M".to_vec()));
+        assert_eq!(decode_base64(""), Some(Vec::new()));
+        for &invalid in &["TQ=", "T===", "====", "TQ==TWFu", "TW=u", "TW!u"] {
+            assert_eq!(decode_base64(invalid), None, "{}", invalid);
+        }
+        for len in 0..8 {
+            let data: Vec<u8> = (0..len).map(|i| 0xf0 ^ i as u8).collect();
+            assert_eq!(decode_base64(&fixture::base64(&data)), Some(data));
+        }
+    }
+
+    #[test]
+    fn picture_block_is_parsed() {
+        let image: Vec<u8> = (0..100).collect();
+        let block = fixture::picture_block("image/png", &image);
+        let picture = parse_picture(&fixture::base64(&block)).unwrap();
+        assert_eq!(picture.picture_type, 3);
+        assert_eq!(picture.mime, "image/png");
+        assert_eq!(picture.description, "cover");
+        assert_eq!((picture.width, picture.height), (2, 1));
+        assert_eq!((picture.depth, picture.colors), (24, 0));
+        assert_eq!(picture.data, image);
+        let truncated = &block[..block.len() - 1];
+        assert_eq!(parse_picture(&fixture::base64(truncated)), None);
+    }
+}
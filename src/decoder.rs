@@ -1,34 +1,233 @@
 use codec::decoder::*;
 use codec::error::*;
-use data::audiosample::formats::S16;
-use data::audiosample::ChannelMap;
+use comment::{parse_picture, read_comment_header, Picture};
+use data::audiosample::formats::{F32, F64, S16, S32};
+use data::audiosample::{ChannelMap, ChannelType, Soniton};
 use data::frame::*;
 use data::packet::Packet;
-use lewton::audio::get_decoded_sample_count;
-use lewton::audio::{read_audio_packet, PreviousWindowRight};
+use data::rational::Rational64;
+use data::timeinfo::TimeInfo;
+use lewton::audio::{read_audio_packet, read_audio_packet_generic};
+use lewton::audio::{AudioReadError, PreviousWindowRight};
+use lewton::header::read_header_ident;
 use lewton::header::read_header_setup;
-use lewton::header::HeaderSet;
-use lewton::header::{read_header_comment, read_header_ident};
+use lewton::header::{CommentHeader, HeaderSet, IdentHeader};
+use pcm::frame_to_le_bytes;
+use setup::{block_windows, is_long_block, parse_setup, silent_packet};
+use setup::{ParsedSetup, SetupSummary};
+use std::any::Any;
 use std::collections::VecDeque;
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub struct Des {
     descr: Descr,
 }
 
+/// Sample format of the decoded frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Packed signed 16-bit samples.
+    S16,
+    /// Packed signed 24-bit samples, left-justified in a 32-bit
+    /// little-endian container with the low byte zero, so the frames are
+    /// full-scale `S32` frames.
+    S24,
+    /// 32-bit floating point samples, as lewton decodes them, packed or
+    /// planar as selected with `Dec::set_planar`.
+    F32,
+    /// Planar 64-bit floating point samples, one plane per channel
+    /// whatever `Dec::set_planar` selects.
+    ///
+    /// This is a convenience for analysis pipelines working in `f64`, the
+    /// samples carry no more precision than the `f32` lewton decodes.
+    F64,
+}
+
+impl OutputFormat {
+    /// Returns the sample format of the frames produced in this format.
+    pub fn soniton(self, planar: bool) -> Soniton {
+        let mut format = match self {
+            OutputFormat::S16 => S16,
+            OutputFormat::S24 => S32,
+            OutputFormat::F32 => F32,
+            OutputFormat::F64 => F64,
+        };
+        format.planar = planar || self == OutputFormat::F64;
+        format
+    }
+}
+
+/// Channel order conventions of the output, see
+/// `Dec::set_output_channel_order`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelOrder {
+    /// The order of section 4.3.9 of the Vorbis I specification, e.g.
+    /// L, C, R, Ls, Rs, LFE for 5.1.
+    Vorbis,
+    /// The order of the WAVE_FORMAT_EXTENSIBLE channel mask bits, as
+    /// expected by Windows sinks, e.g. L, R, C, LFE, Ls, Rs for 5.1, with
+    /// the rear channels ahead of the side channels.
+    WaveExtensible,
+    /// The SMPTE and ITU-R order, e.g. L, R, C, LFE, Ls, Rs for 5.1, with
+    /// the side channels ahead of the rear channels.
+    Smpte,
+}
+
+/// Size class of a Vorbis block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Short,
+    Long,
+}
+
+/// Per-frame metadata, attached to the frames as `TimeInfo::user_private`
+/// when enabled with `Dec::set_frame_flags`.
+///
+/// Use `frame_flags` to retrieve it from a frame.
+#[derive(Clone, Debug, Default)]
+pub struct FrameFlags {
+    /// The frame is the first one decoded after the decoder state was
+    /// reset. The packet before it only primed the overlap, so the frame
+    /// starts abruptly and should be faded in or dropped rather than
+    /// played as is.
+    pub priming: bool,
+    /// The frame starts where decoding can be restarted: a seek index
+    /// should record it, pointing at the packet before the frame's one.
+    ///
+    /// Vorbis packets only decode in pairs, so this holds for the first
+    /// frame after the headers, `flush` or `seek_reset`, whose packets are
+    /// the first ones decoded from freshly primed state.
+    pub seek_point: bool,
+    /// The `user_private` value of the packet the frame was decoded from.
+    pub user_private: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+/// Returns the metadata `Dec` attached to a frame, if any.
+pub fn frame_flags(frame: &Frame) -> Option<&FrameFlags> {
+    frame
+        .t
+        .user_private
+        .as_ref()
+        .and_then(|p| p.downcast_ref::<FrameFlags>())
+}
+
+/// Source of frame buffers, see `Dec::set_frame_provider`.
+pub type FrameProvider = Box<dyn FnMut(&AudioInfo) -> Option<Box<dyn FrameBuffer>> + Send + Sync>;
+
+/// Number of undecodable packets `Dec` skips before giving up, see
+/// `Dec::set_error_budget`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorBudget {
+    /// Most failed packets in a row.
+    pub max_consecutive: usize,
+    /// Most failed packets overall.
+    pub max_total: usize,
+}
+
+/// Measurements of the decoding of one packet, see
+/// `Dec::set_profiling_hook`.
+#[derive(Clone, Copy, Debug)]
+pub struct PacketStats {
+    /// Size of the packet in bytes.
+    pub bytes: usize,
+    /// Number of samples per channel the packet decoded to, before any
+    /// trimming.
+    pub samples: usize,
+    /// Wall-clock time lewton spent decoding the packet.
+    pub duration: Duration,
+}
+
+/// Callback receiving the `PacketStats` of every decoded packet.
+pub type ProfilingHook = Box<dyn FnMut(PacketStats) + Send + Sync>;
+
 pub struct Dec {
     extradata: Option<Vec<u8>>,
-    headers: Option<HeaderSet>,
+    headers: Option<(IdentHeader, CommentHeader, Arc<ParsedSetup>)>,
+    /// The identification, comment and setup header packets as received,
+    /// kept only if `retain_headers`.
+    header_data: Option<[Vec<u8>; 3]>,
+    retain_headers: bool,
     pwr: PreviousWindowRight,
     pending: VecDeque<ArcFrame>,
     info: AudioInfo,
+    output_format: OutputFormat,
+    planar: bool,
+    new_config: bool,
+    max_pending: Option<usize>,
+    peaks: Option<Vec<f32>>,
+    stream_map: ChannelMap,
+    channel_selection: Option<Vec<usize>>,
+    channel_order: ChannelOrder,
+    force_stereo: bool,
+    /// The stream channels making up the output, `None` for all of them
+    /// in stream order.
+    output_channels: Option<Vec<usize>>,
+    installed_setup: Option<Arc<ParsedSetup>>,
+    pool: Vec<ArcFrame>,
+    pool_size: usize,
+    bitrate_window: VecDeque<(usize, usize)>,
+    window_bytes: usize,
+    window_samples: usize,
+    samples_decoded: u64,
+    /// The value of `samples_decoded` where the current chained stream
+    /// started, its granule positions count from there.
+    link_start: u64,
+    gapless: bool,
+    /// The packet being decoded is the last one of the stream.
+    stream_end: bool,
+    intra_only: bool,
+    laced: bool,
+    frame_flags: bool,
+    tail_drain: bool,
+    last_head: Option<[u8; 2]>,
+    /// The granule position the last decoded packet was sent with.
+    last_granule: Option<u64>,
+    total_samples: Option<u64>,
+    config_error: Option<&'static str>,
+    output_hash: Option<u64>,
+    frame_size: Option<usize>,
+    cadence: Vec<Vec<f32>>,
+    cadence_priming: bool,
+    frame_provider: Option<FrameProvider>,
+    /// Buffers taken from the frame provider for the frames of the packet
+    /// being decoded.
+    reserved: VecDeque<Box<dyn FrameBuffer>>,
+    in_band_headers: bool,
+    header_packets: Vec<Vec<u8>>,
+    priming_pending: bool,
+    profiling_hook: Option<ProfilingHook>,
+    resync_window: usize,
+    truncated: bool,
+    /// The last packet sent ended before its header did, which only makes
+    /// it the truncated end of the stream if no packet follows.
+    truncation_pending: bool,
+    error_budget: Option<ErrorBudget>,
+    consecutive_errors: usize,
+    total_errors: usize,
+    budget_exhausted: bool,
 }
 
 impl Dec {
-    fn new() -> Self {
+    /// Creates a decoder for complete streams, which decodes the remaining
+    /// overlap of the last packet when `send_eof` is called.
+    pub fn new() -> Self {
+        Self::with_tail_drain(true)
+    }
+
+    /// Creates a decoder for live streams without a real end. `send_eof`
+    /// never produces a tail frame from the outstanding overlap.
+    pub fn new_streaming() -> Self {
+        Self::with_tail_drain(false)
+    }
+
+    fn with_tail_drain(tail_drain: bool) -> Self {
         Dec {
             extradata: None,
             headers: None,
+            header_data: None,
+            retain_headers: false,
             pwr: PreviousWindowRight::new(),
             pending: VecDeque::with_capacity(1),
             info: AudioInfo {
@@ -38,113 +237,2260 @@ impl Dec {
                 format: Arc::new(S16),
                 block_len: None,
             },
+            output_format: OutputFormat::S16,
+            planar: false,
+            new_config: false,
+            max_pending: None,
+            peaks: None,
+            stream_map: ChannelMap::new(),
+            channel_selection: None,
+            channel_order: ChannelOrder::Vorbis,
+            force_stereo: false,
+            output_channels: None,
+            installed_setup: None,
+            pool: Vec::new(),
+            pool_size: 0,
+            bitrate_window: VecDeque::new(),
+            window_bytes: 0,
+            window_samples: 0,
+            samples_decoded: 0,
+            link_start: 0,
+            gapless: false,
+            stream_end: false,
+            intra_only: false,
+            laced: false,
+            frame_flags: false,
+            tail_drain,
+            last_head: None,
+            last_granule: None,
+            total_samples: None,
+            config_error: None,
+            output_hash: None,
+            frame_size: None,
+            cadence: Vec::new(),
+            cadence_priming: false,
+            frame_provider: None,
+            reserved: VecDeque::new(),
+            in_band_headers: false,
+            header_packets: Vec::new(),
+            priming_pending: false,
+            profiling_hook: None,
+            resync_window: 0,
+            truncated: false,
+            truncation_pending: false,
+            error_budget: None,
+            consecutive_errors: 0,
+            total_errors: 0,
+            budget_exhausted: false,
         }
     }
-}
 
-impl Descriptor for Des {
-    type OutputDecoder = Dec;
+    /// Selects the sample format of the frames produced by `send_packet`.
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.output_format = format;
+        self.update_format();
+    }
 
-    fn create(&self) -> Self::OutputDecoder {
-        Dec::new()
+    /// Selects between packed frames, with all channels interleaved in the
+    /// first plane, and planar frames, with one plane per channel in the
+    /// order of the output `ChannelMap`.
+    pub fn set_planar(&mut self, planar: bool) {
+        self.planar = planar;
+        self.update_format();
     }
 
-    fn describe(&self) -> &Descr {
-        &self.descr
+    fn update_format(&mut self) {
+        self.info.format = Arc::new(self.output_format.soniton(self.planar));
     }
-}
 
-impl Decoder for Dec {
-    fn set_extradata(&mut self, extra: &[u8]) {
-        self.extradata = Some(Vec::from(extra));
+    /// Reports whether a `configure` call on an already configured
+    /// decoder, e.g. at a chained stream boundary, changed the sample rate
+    /// or the channel layout of the output.
+    ///
+    /// The flag is cleared by this call, so a playback sink only needs to
+    /// be reinitialized when it returns `true`.
+    pub fn took_new_config(&mut self) -> bool {
+        let ret = self.new_config;
+        self.new_config = false;
+        ret
+    }
+
+    /// Returns the frame the next `receive_frame` call would return,
+    /// without removing it from the queue.
+    pub fn peek_frame(&self) -> Option<&Frame> {
+        self.pending.front().map(|f| &**f)
     }
-    fn send_packet(&mut self, pkt: &Packet) -> Result<()> {
-        let headers = self.headers.as_ref().unwrap();
-        let mut info = self.info.clone();
-        let samples_per_channel =
-            get_decoded_sample_count(&headers.0, &headers.2, pkt.data.as_slice())
-                .map_err(|_e| Error::InvalidData)?;
-        let channel_count = headers.0.audio_channels as usize;
-        info.samples = samples_per_channel * channel_count;
 
-        let ret = read_audio_packet(&headers.0, &headers.2, pkt.data.as_slice(), &mut self.pwr);
+    /// Returns the number of decoded frames waiting to be received.
+    pub fn pending_frames(&self) -> usize {
+        self.pending.len()
+    }
 
-        if let Ok(samples) = ret {
-            let mut f = Frame::new_default_frame(info, Some(pkt.t.clone()));
-            {
-                let buf: &mut [i16] = f.buf.as_mut_slice(0).unwrap();
-                let sample_count = samples[0].len();
-                for i in 0..sample_count {
-                    for (cn, chan) in samples.iter().enumerate() {
-                        buf[i * channel_count + cn] = chan[i];
-                    }
+    /// Returns the number of samples per channel in the frames waiting to
+    /// be received.
+    pub fn pending_samples(&self) -> usize {
+        self.pending
+            .iter()
+            .map(|f| match f.kind {
+                MediaKind::Audio(ref info) => samples_per_channel(info),
+                MediaKind::Video(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Receives the next pending frame merged with the frames queued after
+    /// it, as long as they share the same layout and the merged frame holds
+    /// at most `max_samples` samples per channel.
+    ///
+    /// The next frame is returned as is if it alone exceeds the limit. The
+    /// merged frame takes the timestamp and the `user_private` value of the
+    /// first frame, and the sum of the durations.
+    pub fn receive_coalesced(&mut self, max_samples: usize) -> Result<ArcFrame> {
+        let info = match self.pending.front() {
+            Some(f) => match f.kind {
+                MediaKind::Audio(ref info) => info.clone(),
+                MediaKind::Video(_) => return Err(Error::InvalidData),
+            },
+            None => return Err(Error::MoreDataNeeded),
+        };
+        let mut lens = Vec::new();
+        let mut total = 0;
+        for f in self.pending.iter() {
+            let len = match f.kind {
+                MediaKind::Audio(ref a) if *a == info => samples_per_channel(a),
+                _ => break,
+            };
+            if !lens.is_empty() && total + len > max_samples {
+                break;
+            }
+            total += len;
+            lens.push(len);
+        }
+        if lens.len() == 1 {
+            return Ok(self.pending.pop_front().unwrap());
+        }
+
+        let mut t = self.pending[0].t.clone();
+        t.duration = self
+            .pending
+            .iter()
+            .take(lens.len())
+            .map(|f| f.t.duration)
+            .sum();
+        let mut merged_info = info.clone();
+        set_samples_per_channel(&mut merged_info, total);
+        let mut merged = self.get_frame(merged_info, t)?;
+        {
+            let dst = Arc::get_mut(&mut merged).unwrap();
+            let sample_size = (info.format.bits as usize).div_ceil(8);
+            let (planes, channels_per_plane) = if info.format.planar {
+                (info.map.len(), 1)
+            } else {
+                (1, info.map.len())
+            };
+            for plane in 0..planes {
+                let buf = dst.buf.as_mut_slice_inner(plane).unwrap();
+                let mut off = 0;
+                for (f, &len) in self.pending.iter().zip(lens.iter()) {
+                    let len = len * channels_per_plane * sample_size;
+                    let src = f.buf.as_slice_inner(plane).unwrap();
+                    buf[off..off + len].copy_from_slice(&src[..len]);
+                    off += len;
                 }
             }
-            self.pending.push_back(Arc::new(f));
-            Ok(())
+        }
+        self.pending.drain(..lens.len());
+        if self.pool_size > 0 {
+            self.pool_frame(&merged);
+        }
+        Ok(merged)
+    }
+
+    /// Limits the number of decoded frames waiting to be received.
+    ///
+    /// `send_packet` rejects a packet, without consuming it, when the
+    /// frames it may produce do not fit under the limit, until frames are
+    /// drained with `receive_frame`. The rejection is an
+    /// `Error::Unsupported`, tell it apart with `is_queue_full` rather than
+    /// by its message. `None` removes the limit.
+    ///
+    /// The room is checked before decoding, for one frame per packet, or
+    /// with `set_frame_size` for the frames the buffered samples and a
+    /// whole long block would fill. An empty queue takes any packet, so
+    /// with a frame size small enough for a packet to fill more frames
+    /// than the limit, the limit is exceeded.
+    pub fn set_max_pending(&mut self, max: Option<usize>) {
+        self.max_pending = max;
+    }
+
+    /// Enables or disables tracking of the per-channel peak levels.
+    pub fn set_peak_metering(&mut self, enable: bool) {
+        self.peaks = if enable { Some(Vec::new()) } else { None };
+    }
+
+    /// Returns the highest absolute sample value seen on each channel
+    /// since the last reset, as decoded with 1.0 for full scale.
+    ///
+    /// The values are not clamped, clipping audio peaks above 1.0 even
+    /// though the integer output formats saturate at full scale.
+    ///
+    /// The slice is empty if peak metering is disabled or nothing has
+    /// been decoded yet.
+    pub fn channel_peaks(&self) -> &[f32] {
+        self.peaks.as_ref().map_or(&[], |p| p.as_slice())
+    }
+
+    /// Restricts the output to the given stream channels, in the given
+    /// order. An empty slice restores the full channel layout.
+    ///
+    /// lewton still decodes every channel, only the interleaving and the
+    /// output `ChannelMap` are reduced. Before `configure` the selection is
+    /// stored and validated once the channel count is known.
+    pub fn set_channel_selection(&mut self, chans: &[usize]) -> Result<()> {
+        let selection = if chans.is_empty() {
+            None
         } else {
-            Err(Error::InvalidData)
+            Some(chans.to_vec())
+        };
+        let previous = std::mem::replace(&mut self.channel_selection, selection);
+        if self.headers.is_some() {
+            if let Err(e) = self.update_channels() {
+                self.channel_selection = previous;
+                return Err(e);
+            }
         }
+        Ok(())
     }
-    fn receive_frame(&mut self) -> Result<ArcFrame> {
-        self.pending.pop_front().ok_or(Error::MoreDataNeeded)
+
+    /// Reorders the output channels to the given convention, updating the
+    /// output `ChannelMap` accordingly. The default is the Vorbis order.
+    ///
+    /// A channel selection set with `set_channel_selection` takes
+    /// precedence, as it already states the order of the channels.
+    pub fn set_output_channel_order(&mut self, order: ChannelOrder) {
+        self.channel_order = order;
+        if self.headers.is_some() {
+            // A reordering of all the channels is always valid
+            let _ = self.update_channels();
+        }
     }
-    fn configure(&mut self) -> Result<()> {
-        let mut extradata = if let Some(ref extradata) = self.extradata {
-            extradata.as_slice()
+
+    /// Outputs mono audio as stereo, with the single channel copied to
+    /// both the left and the right channel, for pipelines only accepting
+    /// stereo. This applies to mono streams as well as to a selection of a
+    /// single channel.
+    pub fn set_force_stereo(&mut self, enable: bool) {
+        self.force_stereo = enable;
+        if self.headers.is_some() {
+            // The channels themselves do not change
+            let _ = self.update_channels();
+        }
+    }
+
+    /// Returns the output layout for the given stream channels.
+    fn output_map(
+        &self,
+        stream_map: &ChannelMap,
+        output: Option<&Vec<usize>>,
+    ) -> Result<ChannelMap> {
+        let map = select_channels(stream_map, output)?;
+        if self.force_stereo && map.len() == 1 {
+            Ok(vorbis_channel_map(2))
         } else {
+            Ok(map)
+        }
+    }
+
+    fn update_channels(&mut self) -> Result<()> {
+        let output = output_channels(
+            &self.stream_map,
+            self.channel_selection.as_ref(),
+            self.channel_order,
+        );
+        self.info.map = self.output_map(&self.stream_map, output.as_ref())?;
+        self.output_channels = output;
+        self.reset_cadence();
+        Ok(())
+    }
+
+    /// Keeps up to `size` emitted frames around and reuses their buffers
+    /// for new frames once every other reference to them is dropped.
+    ///
+    /// A size of 0, the default, allocates every frame anew.
+    pub fn set_frame_pool(&mut self, size: usize) {
+        self.pool_size = size;
+        self.pool.truncate(size);
+    }
+
+    /// Returns a frame for `info`, recycled from the pool if possible.
+    ///
+    /// The returned frame is not shared, so `Arc::get_mut` succeeds on it.
+    /// Fails if the buffer size overflows, before `Frame::new_default_frame`
+    /// would panic on it. av-data allocates infallibly, so a buffer that
+    /// can be represented but not allocated still aborts, only a frame
+    /// provider can fail gracefully on it.
+    fn get_frame(&mut self, info: AudioInfo, t: TimeInfo) -> Result<ArcFrame> {
+        let free = self.pool.iter_mut().position(|f| {
+            let fits = match f.kind {
+                MediaKind::Audio(ref a) => a.samples == info.samples && *a == info,
+                MediaKind::Video(_) => false,
+            };
+            fits && Arc::get_mut(f).is_some()
+        });
+        match free {
+            Some(idx) => {
+                let mut f = self.pool.swap_remove(idx);
+                {
+                    let frame = Arc::get_mut(&mut f).unwrap();
+                    frame.kind = MediaKind::Audio(info);
+                    frame.t = t;
+                    for i in 0..frame.buf.count() {
+                        frame.buf.as_mut_slice_inner(i).unwrap().fill(0);
+                    }
+                }
+                Ok(f)
+            }
+            None if self.frame_provider.is_some() => {
+                let mut buf = match self.reserved.pop_front() {
+                    Some(buf) => buf,
+                    None => {
+                        let provider = self.frame_provider.as_mut().unwrap();
+                        provider(&info).ok_or_else(frame_alloc_failed)?
+                    }
+                };
+                if !buffer_fits(&*buf, &info) {
+                    return Err(frame_alloc_failed());
+                }
+                for i in 0..buf.count() {
+                    buf.as_mut_slice_inner(i).unwrap().fill(0);
+                }
+                Ok(Arc::new(Frame {
+                    kind: MediaKind::Audio(info),
+                    buf,
+                    t,
+                }))
+            }
+            None if !frame_size_fits(&info) => Err(frame_alloc_failed()),
+            None => Ok(Arc::new(Frame::new_default_frame(info, Some(t)))),
+        }
+    }
+
+    /// Makes `send_packet` take the buffers of new frames from `provider`
+    /// instead of allocating them, e.g. to place them in an arena. `None`
+    /// restores the default allocation.
+    ///
+    /// The provider is called with the layout of the frame and must return
+    /// a buffer with one plane, or one plane per channel for planar output,
+    /// large enough for the samples. A buffer that does not fit, or `None`,
+    /// fails the packet like a failed allocation. Frames recycled by
+    /// `set_frame_pool` do not go through the provider.
+    pub fn set_frame_provider(&mut self, provider: Option<FrameProvider>) {
+        self.frame_provider = provider;
+    }
+
+    /// Remembers an emitted frame for later reuse.
+    fn pool_frame(&mut self, f: &ArcFrame) {
+        if self.pool.len() == self.pool_size {
+            // Make room by evicting a frame nobody else uses anymore
+            match self.pool.iter().position(|f| Arc::strong_count(f) == 1) {
+                Some(idx) => {
+                    self.pool.swap_remove(idx);
+                }
+                None => return,
+            }
+        }
+        self.pool.push(f.clone());
+    }
+
+    /// Returns the number of codebooks, floors, residues, mappings and
+    /// modes declared by the setup header, `None` before `configure`.
+    pub fn setup_summary(&self) -> Option<SetupSummary> {
+        self.headers
+            .as_ref()
+            .and_then(|headers| headers.2.summary())
+    }
+
+    /// Returns the identification header of the stream, with its channel
+    /// count, sample rate, bitrates and blocksizes, `None` before
+    /// `configure`.
+    pub fn ident_header(&self) -> Option<&IdentHeader> {
+        self.headers.as_ref().map(|headers| &headers.0)
+    }
+
+    /// Keeps a copy of the header packets when configuring, for
+    /// `to_ffmpeg_extradata`. Disabled by default, as the parsed headers are
+    /// all decoding needs. Disabling it drops the copy.
+    pub fn set_retain_headers(&mut self, enable: bool) {
+        self.retain_headers = enable;
+        if !enable {
+            self.header_data = None;
+        }
+    }
+
+    /// Serializes the header packets of the stream as the extradata ffmpeg
+    /// expects for Vorbis, `None` unless `set_retain_headers` was enabled
+    /// when the decoder was configured.
+    ///
+    /// The layout is the Xiph laced one also used by Matroska: a 2, the
+    /// laced sizes of the identification and comment headers, then the
+    /// three packets as received, so `configure_from` accepts it as well.
+    pub fn to_ffmpeg_extradata(&self) -> Option<Vec<u8>> {
+        let packets = self.header_data.as_ref()?;
+        let mut extradata = vec![2];
+        for packet in &packets[..2] {
+            write_xiph_lacing(&mut extradata, packet.len());
+        }
+        for packet in packets {
+            extradata.extend_from_slice(packet);
+        }
+        Some(extradata)
+    }
+
+    /// Returns the comment header of the stream, `None` before `configure`.
+    ///
+    /// The vendor string and the comments are decoded lossily, invalid
+    /// UTF-8 sequences are replaced with U+FFFD rather than failing the
+    /// configuration or dropping the comment. Comments lacking a `=` are
+    /// skipped.
+    pub fn comment_header(&self) -> Option<&CommentHeader> {
+        self.headers.as_ref().map(|headers| &headers.1)
+    }
+
+    /// Returns the values of every comment named `key`, compared case
+    /// insensitively as field names are, in the order of the comment
+    /// header. Repeated fields, like several `ARTIST` entries, are all
+    /// returned.
+    pub fn comment_values(&self, key: &str) -> Vec<&str> {
+        self.comment_header().map_or(Vec::new(), |comment| {
+            comment
+                .comment_list
+                .iter()
+                .filter(|&(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.as_str())
+                .collect()
+        })
+    }
+
+    /// Returns the first valid picture embedded in the comments as
+    /// `METADATA_BLOCK_PICTURE`, usually the cover art, `None` if there is
+    /// none or before `configure`.
+    pub fn cover_art(&self) -> Option<Picture> {
+        self.comment_header()?
+            .comment_list
+            .iter()
+            .filter(|&(key, _)| key.eq_ignore_ascii_case("METADATA_BLOCK_PICTURE"))
+            .find_map(|(_, value)| parse_picture(value))
+    }
+
+    /// Returns whether the last decoded packet was a short or a long block,
+    /// `None` if nothing was decoded since the last reset.
+    pub fn last_block_kind(&self) -> Option<BlockKind> {
+        let setup = self.headers.as_ref()?.2.info.as_ref()?;
+        match is_long_block(setup, self.last_head?)? {
+            true => Some(BlockKind::Long),
+            false => Some(BlockKind::Short),
+        }
+    }
+
+    /// Returns the parsed setup header of the stream, `None` before
+    /// `configure`.
+    pub fn parsed_setup(&self) -> Option<Arc<ParsedSetup>> {
+        self.headers.as_ref().map(|headers| headers.2.clone())
+    }
+
+    /// Provides a setup header parsed by another decoder, e.g. for the
+    /// previous track of a playlist.
+    ///
+    /// The next `configure` uses it instead of parsing the setup header if
+    /// the setup bytes are the same and the channel count and blocksizes
+    /// agree, and parses the header as usual otherwise. The setup of the
+    /// current stream is reused in the same way at a chained stream
+    /// boundary.
+    pub fn install_setup(&mut self, setup: Arc<ParsedSetup>) {
+        self.installed_setup = Some(setup);
+    }
+
+    /// Returns the bitrate of roughly the last second of decoded audio, in
+    /// bits per second, `None` until some samples have been decoded.
+    ///
+    /// Unlike the bitrate hints of the identification header this follows
+    /// the actual packet sizes, so it can be used to watch adaptive streams.
+    pub fn instantaneous_bitrate(&self) -> Option<u32> {
+        if self.window_samples == 0 {
+            return None;
+        }
+        let bits = self.window_bytes as u64 * 8 * self.info.sample_rate as u64;
+        Some((bits / self.window_samples as u64) as u32)
+    }
+
+    fn update_bitrate(&mut self, bytes: usize, samples: usize) {
+        self.bitrate_window.push_back((bytes, samples));
+        self.window_bytes += bytes;
+        self.window_samples += samples;
+        while let Some(&(bytes, samples)) = self.bitrate_window.front() {
+            if self.window_samples - samples < self.info.sample_rate {
+                break;
+            }
+            self.bitrate_window.pop_front();
+            self.window_bytes -= bytes;
+            self.window_samples -= samples;
+        }
+    }
+
+    fn reset_bitrate(&mut self) {
+        self.bitrate_window.clear();
+        self.window_bytes = 0;
+        self.window_samples = 0;
+    }
+
+    /// Decodes a packet whose ending granule position, as known from the
+    /// container, is `granule`.
+    ///
+    /// The output is trimmed so the total amount of emitted samples per
+    /// channel never exceeds the granule position, which removes the
+    /// padding of the last packet of a stream. If a packet at the start of
+    /// the stream decodes more samples than its granule position allows,
+    /// the surplus is removed from its beginning instead, as happens for
+    /// streams not starting at position zero.
+    ///
+    /// If the last packet before `send_eof` came with its granule position,
+    /// the tail emitted by a decoder created with `new` is trimmed to it
+    /// as well, so the stream ends exactly at its final granule position.
+    pub fn send_packet_with_granule(&mut self, pkt: &Packet, granule: u64) -> Result<()> {
+        self.decode(pkt, Some(granule))
+    }
+
+    /// Decodes the last packet of a stream, as marked by the container,
+    /// whose ending granule position is `granule`.
+    ///
+    /// Like `send_packet_with_granule`, except that the surplus is always
+    /// removed from the end of the packet: a stream short enough to end in
+    /// its first packet is cut to its length rather than taken for one
+    /// starting late.
+    pub fn send_last_packet_with_granule(&mut self, pkt: &Packet, granule: u64) -> Result<()> {
+        self.stream_end = true;
+        let ret = self.decode(pkt, Some(granule));
+        self.stream_end = false;
+        ret
+    }
+
+    fn decode(&mut self, pkt: &Packet, granule: Option<u64>) -> Result<()> {
+        if !self.laced {
+            return self.decode_single(pkt, granule, false);
+        }
+        let packets = match split_laced(&pkt.data) {
+            Some(packets) => packets,
+            None => return self.recover(),
+        };
+        // Rejecting a packet once some of it was decoded would make sending
+        // it again decode that part twice, so room is made for all of it
+        self.check_room(packets.len())?;
+        let max_pending = self.max_pending.take();
+        let mut ret = Ok(());
+        let last = packets.len() - 1;
+        for (i, data) in packets.into_iter().enumerate() {
+            let sub = Packet {
+                data: data.to_vec(),
+                t: if i == 0 {
+                    pkt.t.clone()
+                } else {
+                    self.sample_to_timestamp(self.samples_decoded)
+                        .unwrap_or_default()
+                },
+                ..Default::default()
+            };
+            ret = self.decode_single(&sub, granule.filter(|_| i == last), false);
+            if ret.is_err() {
+                break;
+            }
+        }
+        self.max_pending = max_pending;
+        ret
+    }
+
+    /// Decodes one Vorbis packet, `synthetic` if it was made up by the
+    /// decoder rather than sent, so it is neither a header nor accounted
+    /// for in the profiling and bitrate statistics.
+    fn decode_single(&mut self, pkt: &Packet, granule: Option<u64>, synthetic: bool) -> Result<()> {
+        if self.in_band_headers && !synthetic {
+            let mut data = pkt.data.as_slice();
+            if self.headers.is_none() && self.header_packets.is_empty() {
+                if let Some(offset) = find_ident(data, self.resync_window) {
+                    data = &data[offset..];
+                }
+            }
+            let ident = has_magic(data, 1);
+            if ident {
+                // A new stream starts, possibly chained to the current one
+                self.header_packets.clear();
+            }
+            if ident || self.headers.is_none() || !self.header_packets.is_empty() {
+                return self.collect_header(data);
+            }
+        }
+        if self.budget_exhausted {
+            return Err(Error::Unsupported(ERROR_BUDGET_EXHAUSTED.to_owned()));
+        }
+        self.check_room(1)?;
+        if self.headers.is_none() {
             return Err(Error::ConfigurationIncomplete);
+        }
+        // Failing once lewton advanced the overlap would lose the audio of
+        // the packet, and sending it again would decode the overlap twice
+        self.reserve_frames(&pkt.data)?;
+        if !synthetic {
+            // More audio follows, the short packet was not the end
+            self.truncated = false;
+            self.truncation_pending = false;
+        }
+        let headers = self.headers.as_ref().unwrap();
+        let priming = self.pwr.is_empty() || self.priming_pending;
+        let start = self
+            .profiling_hook
+            .as_ref()
+            .filter(|_| !synthetic)
+            .map(|_| Instant::now());
+        let ret = if self.intra_only {
+            decode_intra(headers, pkt.data.as_slice())
+        } else {
+            read_audio_packet_generic::<Vec<Vec<f32>>>(
+                &headers.0,
+                &headers.2.header,
+                pkt.data.as_slice(),
+                &mut self.pwr,
+            )
         };
-        // We must start with a 2 as per matroska encapsulation spec
-        if extradata.is_empty() || extradata[0] != 2 {
-            return Err(Error::InvalidData);
+
+        if ret.is_ok() {
+            self.consecutive_errors = 0;
         }
-        extradata = &extradata[1..];
-        let ident_len = read_xiph_lacing(&mut extradata)? as usize;
-        let comment_len = read_xiph_lacing(&mut extradata)? as usize;
-
-        let ident = read_header_ident(&extradata[0..ident_len]).map_err(|_e| Error::InvalidData)?;
-        extradata = &extradata[ident_len..];
-        let comment =
-            read_header_comment(&extradata[0..comment_len]).map_err(|_e| Error::InvalidData)?;
-        extradata = &extradata[comment_len..];
-        let setup = read_header_setup(
-            extradata,
-            ident.audio_channels,
-            (ident.blocksize_0, ident.blocksize_1),
-        )
-        .map_err(|_e| Error::InvalidData)?;
+        if let Ok(mut samples) = ret {
+            self.last_granule = granule;
+            if let (Some(hook), Some(start)) = (self.profiling_hook.as_mut(), start) {
+                hook(PacketStats {
+                    bytes: pkt.data.len(),
+                    samples: samples[0].len(),
+                    duration: start.elapsed(),
+                });
+            }
+            self.last_head = Some(packet_head(&pkt.data));
+            if !synthetic {
+                self.update_bitrate(pkt.data.len(), samples[0].len());
+            }
+            let mut trimmed = false;
+            if let Some(granule) = granule {
+                // The tail always ends the stream
+                let end = self.stream_end || synthetic;
+                trimmed |= trim_to_granule(
+                    &mut samples,
+                    self.samples_decoded - self.link_start,
+                    granule,
+                    end,
+                );
+            }
+            if let Some(total) = self.total_samples {
+                trimmed |= truncate_at(&mut samples, self.samples_decoded, total);
+            }
+            if samples[0].is_empty() {
+                // Either entirely past the end of the stream, or the first
+                // packet after a reset, which only fills the overlap
+                self.priming_pending = priming && !trimmed;
+                return Ok(());
+            }
+            self.priming_pending = false;
+            self.samples_decoded += samples[0].len() as u64;
+            if let Some(ref selection) = self.output_channels {
+                samples = selection
+                    .iter()
+                    .map(|&c| std::mem::take(&mut samples[c]))
+                    .collect();
+            }
+            if self.force_stereo && samples.len() == 1 {
+                let right = samples[0].clone();
+                samples.push(right);
+            }
+            if let Some(ref mut peaks) = self.peaks {
+                update_peaks(peaks, &samples);
+            }
+            if let Some(size) = self.frame_size {
+                self.cadence.resize(samples.len(), Vec::new());
+                for (buf, chan) in self.cadence.iter_mut().zip(samples) {
+                    buf.extend(chan);
+                }
+                self.cadence_priming |= priming;
+                while self.cadence[0].len() >= size {
+                    self.emit_cadence(size)?;
+                }
+                return Ok(());
+            }
+            let mut t = pkt.t.clone();
+            if self.frame_flags {
+                let flags = FrameFlags {
+                    priming,
+                    seek_point: priming,
+                    user_private: t.user_private.take(),
+                };
+                t.user_private = Some(Arc::new(flags));
+            }
+            let mut info = self.info.clone();
+            // Sized from the actual output, the decoded length varies with
+            // the block sizes on both sides of each overlap
+            set_samples_per_channel(&mut info, samples[0].len());
+            self.emit_frame(info, &samples, t)
+        } else if let Err(AudioReadError::EndOfPacket) = ret {
+            // The packet ends before its header does, as happens to the
+            // last packet of a cut off file, which only the next packet
+            // or `send_eof` tells. lewton leaves the overlap untouched, so
+            // the stream can still end cleanly.
+            self.truncation_pending = true;
+            Ok(())
+        } else {
+            self.recover()
+        }
+    }
+
+    /// Returns the number of samples per channel decoding `data` yields,
+    /// before any trimming, `None` if it can not be told.
+    fn decoded_len(&self, data: &[u8]) -> Option<usize> {
+        let (n, left, right) = self.block_sizes(packet_head(data))?;
+        if self.intra_only {
+            // The block followed by silence, see `decode_intra`
+            return Some(right / 2);
+        }
+        if self.pwr.is_empty() {
+            return Some(0);
+        }
+        let (_, _, prev) = self.block_sizes(self.last_head?)?;
+        Some(overlap_len(prev, (n, left, right)))
+    }
 
-        self.info.sample_rate = ident.audio_sample_rate as usize;
-        self.info.map = ChannelMap::default_map(ident.audio_channels as usize);
+    /// Returns the size of the audio block starting with `head` and the
+    /// sizes of its left and right windows.
+    fn block_sizes(&self, head: [u8; 2]) -> Option<(usize, usize, usize)> {
+        let headers = self.headers.as_ref()?;
+        let (long, left, right) = block_windows(headers.2.info.as_ref()?, head)?;
+        let size = |long: bool| {
+            1 << if long {
+                headers.0.blocksize_1
+            } else {
+                headers.0.blocksize_0
+            }
+        };
+        Some((size(long), size(left && long), size(right && long)))
+    }
 
-        let headers = (ident, comment, setup);
-        self.headers = Some(headers);
+    /// Makes sure the frames decoding `data` emits can be allocated, taking
+    /// their buffers from the frame provider upfront.
+    fn reserve_frames(&mut self, data: &[u8]) -> Result<()> {
+        self.reserved.clear();
+        let len = match self.decoded_len(data) {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+        let (count, len) = match self.frame_size {
+            Some(size) => (
+                (self.cadence.first().map_or(0, Vec::len) + len) / size,
+                size,
+            ),
+            None => (usize::from(len > 0), len),
+        };
+        if count == 0 {
+            return Ok(());
+        }
+        let mut info = self.info.clone();
+        set_samples_per_channel(&mut info, len);
+        if !frame_size_fits(&info) {
+            return Err(frame_alloc_failed());
+        }
+        if let Some(provider) = self.frame_provider.as_mut() {
+            for _ in 0..count {
+                match provider(&info) {
+                    Some(buf) if buffer_fits(&*buf, &info) => self.reserved.push_back(buf),
+                    _ => {
+                        self.reserved.clear();
+                        return Err(frame_alloc_failed());
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<()> {
+    /// Returns the most frames decoding `packets` packets can queue.
+    fn max_packet_frames(&self, packets: usize) -> usize {
+        match (self.frame_size, self.headers.as_ref()) {
+            // No packet decodes to more than a long block
+            (Some(size), Some(headers)) => {
+                let buffered = self.cadence.first().map_or(0, Vec::len);
+                (buffered + (packets << headers.0.blocksize_1)) / size
+            }
+            _ => packets,
+        }
+    }
+
+    /// Checks that the frames of `packets` packets fit in the queue.
+    fn check_room(&self, packets: usize) -> Result<()> {
+        match self.max_pending {
+            Some(max)
+                if !self.pending.is_empty()
+                    && self.pending.len() + self.max_packet_frames(packets) > max =>
+            {
+                Err(Error::Unsupported(QUEUE_FULL.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Installs a callback invoked with the size and decoding time of every
+    /// successfully decoded packet, to find the expensive ones. `None`, the
+    /// default, removes it, and the packets are not timed at all.
+    pub fn set_profiling_hook(&mut self, hook: Option<ProfilingHook>) {
+        self.profiling_hook = hook;
+    }
+
+    /// Makes `send_packet` accept the three header packets in front of the
+    /// audio packets, as carried by Ogg and other containers without
+    /// extradata, instead of requiring `configure`.
+    ///
+    /// Until the identification, comment and setup headers have arrived in
+    /// this order, audio packets are rejected with `Error::MoreDataNeeded`
+    /// without being decoded, and so are header packets out of order. An
+    /// identification header after the stream was configured restarts the
+    /// collection for a chained stream.
+    pub fn set_in_band_headers(&mut self, enable: bool) {
+        self.in_band_headers = enable;
+        self.header_packets.clear();
+    }
+
+    /// Lets the first identification header of an in-band stream be
+    /// preceded by up to `window` stray bytes, as found in sloppy captures.
+    /// 0, the default, requires it at the start of the packet.
+    ///
+    /// Only the packets sent before the first identification header are
+    /// searched, so audio packets can not be mistaken for one.
+    pub fn set_header_resync(&mut self, window: usize) {
+        self.resync_window = window;
+    }
+
+    /// Decodes chained streams straight through, as for the tracks of a
+    /// gapless album, when the next stream has the same sample rate,
+    /// channel count and block sizes as the current one.
+    ///
+    /// The overlap of the last packet is then not dropped but blended into
+    /// the first packet of the next stream, the decoded sample count keeps
+    /// growing and no new configuration is reported. Streams with other
+    /// parameters still reset the decoder, as they can not be overlapped.
+    ///
+    /// `VorbisStreamDecoder` no longer ends a stream at its last page
+    /// either, call `send_eof` once the last one has been pushed.
+    pub fn set_gapless(&mut self, enable: bool) {
+        self.gapless = enable;
+    }
+
+    /// Decodes every packet on its own, from a fresh overlap state, so the
+    /// output of a packet no longer depends on the packets before it.
+    ///
+    /// Each frame then holds the right half of the packet's window faded
+    /// out against silence, instead of its overlap with the previous one.
+    /// The result is only an approximation of the stream, with a gap or
+    /// a discontinuity at every block boundary: good enough for a rough
+    /// spectrum or loudness analysis, but not for playback. In exchange,
+    /// packets can be decoded in any order, or in parallel by one decoder
+    /// per thread sharing the setup through `install_setup`.
+    pub fn set_intra_only(&mut self, enable: bool) {
+        self.intra_only = enable;
         self.pwr = PreviousWindowRight::new();
+    }
+
+    /// Makes `send_packet` accept container packets holding several Vorbis
+    /// packets, each decoded to its own frame.
+    ///
+    /// Vorbis packets do not carry their length, their end is only known
+    /// from the container, so plain concatenations can not be split. The
+    /// packets have to be Xiph laced as in Matroska: a byte holding the
+    /// number of packets minus one, the laced sizes of all but the last
+    /// packet, then the packets. A single packet is therefore prefixed by
+    /// a 0 byte. Only the first frame keeps the timestamp of the container
+    /// packet, the others are timed from the decoded sample position as by
+    /// `sample_to_timestamp`, and a granule position applies to the last
+    /// one. The limit of `set_max_pending` applies to the container packet
+    /// as a whole, with room for the frames of all the packets it holds. A
+    /// packet failing to decode drops the packets after it.
+    pub fn set_laced_packets(&mut self, enable: bool) {
+        self.laced = enable;
+    }
+
+    pub(crate) fn gapless(&self) -> bool {
+        self.gapless
+    }
+
+    pub(crate) fn audio_info(&self) -> &AudioInfo {
+        &self.info
+    }
+
+    fn collect_header(&mut self, data: &[u8]) -> Result<()> {
+        let packet_type = [1, 3, 5][self.header_packets.len()];
+        if !has_magic(data, packet_type) {
+            return Err(Error::MoreDataNeeded);
+        }
+        self.header_packets.push(data.to_vec());
+        if self.header_packets.len() < 3 {
+            return Ok(());
+        }
+        let headers = std::mem::take(&mut self.header_packets);
+        self.configure_headers(&headers[0], &headers[1], &headers[2])
+    }
+
+    /// Converts the samples into a frame and queues it.
+    ///
+    /// The duration of the frame is set in the timebase of `t`, or in
+    /// samples per channel if it has none.
+    fn emit_frame(&mut self, info: AudioInfo, samples: &[Vec<f32>], mut t: TimeInfo) -> Result<()> {
+        let len = samples_per_channel(&info) as u64;
+        t.duration = Some(match t.timebase {
+            Some(tb) if *tb.numer() > 0 && *tb.denom() > 0 => {
+                let d = i128::from(len) * i128::from(*tb.denom())
+                    / (i128::from(*tb.numer()) * self.info.sample_rate as i128);
+                d as u64
+            }
+            _ => len,
+        });
+        let mut f = self.get_frame(info, t)?;
+        {
+            let frame = Arc::get_mut(&mut f).unwrap();
+            let planar = self.info.format.planar;
+            match self.output_format {
+                OutputFormat::S16 => write_samples::<i16>(frame, samples, planar),
+                OutputFormat::S24 => write_samples::<S24>(frame, samples, planar),
+                OutputFormat::F32 => write_samples::<f32>(frame, samples, planar),
+                OutputFormat::F64 => write_samples::<f64>(frame, samples, planar),
+            }
+        }
+        if let Some(ref mut hash) = self.output_hash {
+            for &b in frame_to_le_bytes(&f).iter() {
+                *hash = (*hash ^ u64::from(b)).wrapping_mul(FNV_PRIME);
+            }
+        }
+        if self.pool_size > 0 {
+            self.pool_frame(&f);
+        }
+        self.pending.push_back(f);
         Ok(())
     }
-}
 
-fn read_xiph_lacing(arr: &mut &[u8]) -> Result<u64> {
-    let mut r = 0;
-    loop {
-        if arr.is_empty() {
-            return Err(Error::InvalidData);
+    /// Emits the first `len` buffered samples per channel as a frame.
+    fn emit_cadence(&mut self, len: usize) -> Result<()> {
+        let start = self.samples_decoded - self.cadence[0].len() as u64;
+        let samples: Vec<Vec<f32>> = self
+            .cadence
+            .iter_mut()
+            .map(|buf| buf.drain(..len).collect())
+            .collect();
+        let mut info = self.info.clone();
+        set_samples_per_channel(&mut info, len);
+        let mut t = self.sample_to_timestamp(start).unwrap_or_default();
+        if self.frame_flags {
+            let flags = FrameFlags {
+                priming: self.cadence_priming,
+                seek_point: self.cadence_priming,
+                user_private: None,
+            };
+            t.user_private = Some(Arc::new(flags));
         }
-        let v = arr[0] as u64;
-        *arr = &arr[1..];
-        r += v;
-        if v < 255 {
-            return Ok(r);
+        self.cadence_priming = false;
+        self.emit_frame(info, &samples, t)
+    }
+
+    /// Makes every emitted frame hold exactly `size` samples per channel,
+    /// regardless of the Vorbis block sizes. `None`, the default, emits one
+    /// frame per packet.
+    ///
+    /// Set to the period of an audio device, e.g. 480 samples for 10ms at
+    /// 48kHz, every frame can be handed to it as is.
+    ///
+    /// Decoded samples are buffered until enough are available. The
+    /// remainder is emitted as a shorter frame by `send_eof`, and dropped by
+    /// `flush` and `configure`. The frames are timed from the decoded sample
+    /// position as by `sample_to_timestamp`, as they no longer match a
+    /// packet, and their `FrameFlags` carry no packet `user_private` value.
+    ///
+    /// Samples buffered when the size is changed are dropped.
+    pub fn set_frame_size(&mut self, size: Option<usize>) {
+        self.frame_size = size.filter(|&size| size > 0);
+        self.reset_cadence();
+    }
+
+    fn reset_cadence(&mut self) {
+        self.cadence.clear();
+        self.cadence_priming = false;
+    }
+
+    /// Skips undecodable packets instead of failing on them, until `budget`
+    /// is exhausted. `None`, the default, fails on every such packet.
+    ///
+    /// Once more consecutive or total packets failed than the budget
+    /// allows, `send_packet` returns a terminal error, see
+    /// `is_error_budget_exhausted`, for this packet and all the following
+    /// ones until `flush`. A successfully decoded packet resets the
+    /// consecutive count.
+    pub fn set_error_budget(&mut self, budget: Option<ErrorBudget>) {
+        self.error_budget = budget;
+        self.reset_error_budget();
+    }
+
+    /// Returns the number of undecodable packets skipped within the error
+    /// budget since the last `flush`.
+    pub fn recovered_errors(&self) -> usize {
+        self.total_errors
+    }
+
+    fn reset_error_budget(&mut self) {
+        self.consecutive_errors = 0;
+        self.total_errors = 0;
+        self.budget_exhausted = false;
+    }
+
+    /// Accounts for an undecodable packet.
+    fn recover(&mut self) -> Result<()> {
+        let budget = match self.error_budget {
+            Some(budget) => budget,
+            None => return Err(Error::InvalidData),
+        };
+        self.consecutive_errors += 1;
+        self.total_errors += 1;
+        if self.consecutive_errors > budget.max_consecutive || self.total_errors > budget.max_total
+        {
+            self.budget_exhausted = true;
+            return Err(Error::Unsupported(ERROR_BUDGET_EXHAUSTED.to_owned()));
         }
+        Ok(())
     }
-}
 
-pub const VORBIS_DESCR: &Des = &Des {
+    /// Reports whether the stream ended by `send_eof` was cut off, its last
+    /// packet being too short to even hold its header.
+    ///
+    /// Such a packet is skipped without an error, as there is nothing to
+    /// recover from it, and the stream ends as usual. Short packets within
+    /// the stream, such as the empty packets Ogg allows, are skipped the
+    /// same way and do not count as truncation.
+    pub fn was_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Sets the duration of the stream in samples per channel, as declared
+    /// by the container.
+    ///
+    /// Decoded audio beyond it, such as the padding of the last packet or
+    /// the tail emitted by `send_eof`, is dropped, so the emitted total
+    /// matches the declared duration.
+    pub fn set_total_samples(&mut self, total: Option<u64>) {
+        self.total_samples = total;
+    }
+
+    /// Enables attaching `FrameFlags` to every emitted frame.
+    ///
+    /// The packet's own `user_private` value is preserved inside the flags.
+    pub fn set_frame_flags(&mut self, enable: bool) {
+        self.frame_flags = enable;
+    }
+
+    /// Signals the end of the stream.
+    ///
+    /// A decoder created with `new` emits one more frame holding the right
+    /// half of the last decoded block faded out, which is otherwise held
+    /// back waiting for the next packet to overlap with, and then starts
+    /// over as after `flush`. The frame is timed from the decoded sample
+    /// position as by `sample_to_timestamp`. For a decoder created with
+    /// `new_streaming` this is skipped, so an interrupted live stream can
+    /// not produce a spurious short frame.
+    ///
+    /// If the last packet was sent with `send_packet_with_granule`, only
+    /// the part of the tail up to its granule position is emitted, which is
+    /// usually none of it.
+    ///
+    /// With a fixed frame size, the samples still buffered are emitted as
+    /// a final shorter frame in both cases.
+    ///
+    /// The tail is subject to the limit of `set_max_pending`. If the queue
+    /// is full the overlap is kept and `send_eof` can be called again once
+    /// frames have been received.
+    pub fn send_eof(&mut self) -> Result<()> {
+        if self.truncation_pending {
+            self.truncation_pending = false;
+            self.truncated = true;
+        }
+        if self.tail_drain && !self.pwr.is_empty() {
+            self.drain_tail()?;
+        }
+        match self.cadence.first().map(Vec::len) {
+            Some(len) if len > 0 => self.emit_cadence(len),
+            _ => Ok(()),
+        }
+    }
+
+    fn drain_tail(&mut self) -> Result<()> {
+        let pkt = match (self.headers.as_ref(), self.last_head) {
+            (Some(headers), Some(head)) => headers
+                .2
+                .info
+                .as_ref()
+                .and_then(|setup| silent_packet(setup, head, headers.0.audio_channels)),
+            _ => None,
+        };
+        // The final granule position dictates how much of the tail belongs
+        // to the stream
+        let granule = self.last_granule;
+        if let Some(data) = pkt {
+            let pkt = Packet {
+                data,
+                t: self
+                    .sample_to_timestamp(self.samples_decoded)
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            // Kept on failure, so a rejected tail can be drained again
+            self.decode_single(&pkt, granule, true)?;
+        }
+        self.pwr = PreviousWindowRight::new();
+        self.last_head = None;
+        self.last_granule = None;
+        Ok(())
+    }
+
+    /// Decodes a whole stream, handing every frame to `sink` as soon as it
+    /// is decoded, so memory use does not grow with the stream length.
+    ///
+    /// `send_eof` is called after the last packet. Decoding stops at the
+    /// first packet that fails.
+    pub fn decode_all_with<I, F>(&mut self, packets: I, mut sink: F) -> Result<()>
+    where
+        I: IntoIterator<Item = Packet>,
+        F: FnMut(ArcFrame),
+    {
+        for pkt in packets {
+            self.decode(&pkt, None)?;
+            while let Some(f) = self.pending.pop_front() {
+                sink(f);
+            }
+        }
+        self.send_eof()?;
+        while let Some(f) = self.pending.pop_front() {
+            sink(f);
+        }
+        Ok(())
+    }
+
+    /// Skips the first `n` of `packets`, so the next packet sent is decoded
+    /// as if all of them had been, and counts their samples as decoded.
+    ///
+    /// Only the last skipped packet is actually decoded, to prime the
+    /// overlap, the length of the others follows from their block sizes.
+    /// No frames are emitted, and the samples buffered for
+    /// `set_frame_size` are dropped. With `set_laced_packets`, `n` counts
+    /// container packets, each skipping all the Vorbis packets it holds.
+    pub fn skip_packets<I>(&mut self, packets: I, n: usize) -> Result<()>
+    where
+        I: IntoIterator<Item = Packet>,
+    {
+        let headers = match self.headers.as_ref() {
+            Some(headers) => headers,
+            None => return Err(Error::ConfigurationIncomplete),
+        };
+        let mut prev = if self.pwr.is_empty() {
+            None
+        } else {
+            self.last_head
+                .and_then(|head| self.block_sizes(head))
+                .map(|(_, _, right)| right)
+        };
+        let mut skipped = 0;
+        let mut last = None;
+        for pkt in packets.into_iter().take(n) {
+            let data = if self.laced {
+                split_laced(&pkt.data).ok_or(Error::InvalidData)?
+            } else {
+                vec![pkt.data.as_slice()]
+            };
+            for data in data {
+                let block = self
+                    .block_sizes(packet_head(data))
+                    .ok_or(Error::InvalidData)?;
+                if let Some(prev) = prev {
+                    skipped += overlap_len(prev, block) as u64;
+                }
+                prev = Some(block.2);
+                last = Some(data.to_vec());
+            }
+        }
+        let data = match last {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        // Decoded on its own, the last packet only fills the overlap
+        let mut pwr = PreviousWindowRight::new();
+        read_audio_packet_generic::<Vec<Vec<f32>>>(
+            &headers.0,
+            &headers.2.header,
+            data.as_slice(),
+            &mut pwr,
+        )
+        .map_err(|_e| Error::InvalidData)?;
+        self.pwr = pwr;
+        self.last_head = Some(packet_head(&data));
+        self.priming_pending = false;
+        self.samples_decoded += skipped;
+        // Buffered samples, a short packet and the bitrate window are from
+        // before the skipped packets
+        self.reset_cadence();
+        self.truncation_pending = false;
+        self.truncated = false;
+        self.reset_bitrate();
+        Ok(())
+    }
+
+    /// Prepares the decoder for decoding from a new position after a
+    /// container level seek.
+    ///
+    /// Like `flush` it drops the overlap state, and it also discards the
+    /// frames not received yet and continues counting decoded samples from
+    /// `new_position`. The first frame decoded afterwards is a priming
+    /// frame, flagged as such if `set_frame_flags` is enabled.
+    pub fn seek_reset(&mut self, new_position: u64) {
+        // Flushing can not fail
+        let _ = self.flush();
+        self.pending.clear();
+        self.samples_decoded = new_position;
+        self.link_start = 0;
+    }
+
+    /// Converts a sample index, counted per channel, into a timestamp in
+    /// units of the stream's sample rate, `None` before `configure`.
+    ///
+    /// This matches how Ogg and Matroska demuxers time Vorbis packets, so
+    /// it is consistent with the timestamps carried over to the frames.
+    pub fn sample_to_timestamp(&self, sample_index: u64) -> Option<TimeInfo> {
+        self.headers.as_ref()?;
+        Some(TimeInfo {
+            pts: Some(sample_index as i64),
+            timebase: Some(Rational64::new(1, self.info.sample_rate as i64)),
+            ..Default::default()
+        })
+    }
+
+    /// Converts the presentation timestamp of `t` back into a sample index,
+    /// the inverse of `sample_to_timestamp`.
+    ///
+    /// Returns `None` before `configure`, for negative timestamps and if
+    /// the pts or the timebase are missing.
+    pub fn timestamp_to_sample(&self, t: &TimeInfo) -> Option<u64> {
+        self.headers.as_ref()?;
+        let (pts, timebase) = (t.pts?, t.timebase?);
+        let samples =
+            i128::from(pts) * i128::from(*timebase.numer()) * self.info.sample_rate as i128
+                / i128::from(*timebase.denom());
+        if samples < 0 {
+            None
+        } else {
+            Some(samples as u64)
+        }
+    }
+
+    /// Returns the worst case latency, in samples per channel, between a
+    /// packet entering the decoder and its audio being emitted, 0 before
+    /// `configure`.
+    ///
+    /// Each packet only completes the overlap with the previous one, the
+    /// right half of its own window is held back until the next packet
+    /// arrives: up to half a long block. A fixed frame size set with
+    /// `set_frame_size` adds the samples buffered towards the next frame.
+    pub fn latency_samples(&self) -> usize {
+        let overlap = self
+            .headers
+            .as_ref()
+            .map_or(0, |headers| (1 << headers.0.blocksize_1) / 2);
+        match self.frame_size {
+            // Up to one sample short of a whole frame waits in the buffer
+            Some(size) if overlap > 0 => overlap + size - 1,
+            _ => overlap,
+        }
+    }
+
+    /// Returns the number of samples per channel decoded since the stream
+    /// was configured, or since the position given to `seek_reset`.
+    pub fn samples_decoded(&self) -> u64 {
+        self.samples_decoded
+    }
+
+    /// Configures the decoder directly from Matroska style extradata.
+    ///
+    /// Unlike `set_extradata` followed by `configure` the bytes are only
+    /// borrowed, nothing but the parsed headers is kept afterwards.
+    pub fn configure_from(&mut self, extradata: &[u8]) -> Result<()> {
+        self.config_error = None;
+        let (ident_data, comment_data, setup_data) = match split_headers(extradata) {
+            Ok(headers) => headers,
+            Err(detail) => return Err(self.invalid(detail)),
+        };
+        self.configure_headers(ident_data, comment_data, setup_data)
+    }
+
+    /// Configures the decoder from the three header packets, whose magic
+    /// has been checked.
+    fn configure_headers(
+        &mut self,
+        ident_data: &[u8],
+        comment_data: &[u8],
+        setup_data: &[u8],
+    ) -> Result<()> {
+        self.config_error = None;
+        // lewton rejects these too, but without telling why
+        if ident_data.len() < 11 {
+            return Err(self.invalid("truncated identification header"));
+        }
+        if ident_data[7..11] != [0; 4] {
+            return Err(self.invalid("unsupported vorbis version, only version 0 is defined"));
+        }
+        let ident = match read_header_ident(ident_data) {
+            Ok(ident) => ident,
+            Err(_) => return Err(self.invalid("malformed identification header")),
+        };
+        let comment = match read_comment_header(comment_data) {
+            Some(comment) => comment,
+            None => return Err(self.invalid("malformed comment header")),
+        };
+        let blocksizes = (ident.blocksize_0, ident.blocksize_1);
+        let key = setup_key(setup_data, ident.audio_channels, blocksizes);
+        let hash = setup_hash(&key);
+        // The hash only saves comparing the bytes of every mismatch
+        let cached = self
+            .installed_setup
+            .iter()
+            .chain(self.headers.as_ref().map(|headers| &headers.2))
+            .find(|setup| setup.hash == hash && setup.key == key)
+            .cloned();
+        let setup = match cached {
+            Some(setup) => setup,
+            None => {
+                let info = parse_setup(setup_data, ident.audio_channels);
+                match read_header_setup(setup_data, ident.audio_channels, blocksizes) {
+                    Ok(header) => Arc::new(ParsedSetup {
+                        hash,
+                        key,
+                        header,
+                        info: info.ok(),
+                    }),
+                    // lewton does not say what is wrong, the walk over the
+                    // header usually can
+                    Err(_) => {
+                        return Err(self.invalid(info.err().unwrap_or("malformed setup header")))
+                    }
+                }
+            }
+        };
+
+        let sample_rate = ident.audio_sample_rate as usize;
+        let stream_map = vorbis_channel_map(ident.audio_channels as usize);
+        let output = output_channels(
+            &stream_map,
+            self.channel_selection.as_ref(),
+            self.channel_order,
+        );
+        let map = self.output_map(&stream_map, output.as_ref())?;
+        let changed = self.headers.is_some()
+            && (self.info.sample_rate != sample_rate || self.info.map != map);
+        if changed {
+            self.new_config = true;
+        }
+        // lewton can only overlap blocks of the same sizes and channels
+        let continuous = self.gapless
+            && !changed
+            && self.headers.as_ref().is_some_and(|headers| {
+                headers.0.audio_channels == ident.audio_channels
+                    && (headers.0.blocksize_0, headers.0.blocksize_1) == blocksizes
+            });
+        self.info.sample_rate = sample_rate;
+        self.info.map = map;
+        self.stream_map = stream_map;
+        self.output_channels = output;
+        if continuous {
+            self.link_start = self.samples_decoded;
+        } else {
+            // The previous window belongs to the old stream
+            self.pwr = PreviousWindowRight::new();
+            self.last_granule = None;
+            self.reset_cadence();
+            self.samples_decoded = 0;
+            self.link_start = 0;
+        }
+
+        let headers = (ident, comment, setup);
+        self.headers = Some(headers);
+        self.header_data = if self.retain_headers {
+            Some([
+                ident_data.to_vec(),
+                comment_data.to_vec(),
+                setup_data.to_vec(),
+            ])
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Records why the headers were rejected.
+    fn invalid(&mut self, detail: &'static str) -> Error {
+        self.config_error = Some(detail);
+        Error::InvalidData
+    }
+
+    /// Describes why the last `configure` call rejected the extradata,
+    /// `None` if it succeeded or failed for another reason.
+    pub fn config_error(&self) -> Option<&'static str> {
+        self.config_error
+    }
+
+    /// Enables or disables hashing the emitted PCM.
+    ///
+    /// The hash covers the little-endian sample bytes of every frame, as
+    /// produced by `pcm::frame_to_le_bytes`, so it is meant for regression
+    /// tooling comparing decodes rather than for normal playback.
+    pub fn set_output_hash(&mut self, enable: bool) {
+        self.output_hash = if enable { Some(FNV_OFFSET) } else { None };
+    }
+
+    /// Returns the FNV-1a hash of the PCM emitted since hashing was enabled
+    /// or last reset by `reset_output_hash` or `flush`, 0 if hashing is
+    /// disabled.
+    pub fn output_hash(&self) -> u64 {
+        self.output_hash.unwrap_or(0)
+    }
+
+    /// Restarts the output hash.
+    pub fn reset_output_hash(&mut self) {
+        if let Some(ref mut hash) = self.output_hash {
+            *hash = FNV_OFFSET;
+        }
+    }
+
+    /// Resets the peak levels to zero.
+    pub fn reset_peaks(&mut self) {
+        if let Some(ref mut peaks) = self.peaks {
+            peaks.clear();
+        }
+    }
+}
+
+impl Default for Dec {
+    fn default() -> Self {
+        Dec::new()
+    }
+}
+
+/// Collects the options of a `Dec` and creates it in one go.
+///
+/// Every option defaults to the behavior of a decoder created with
+/// `Dec::new` and maps to the `Dec` setter of the same name.
+#[derive(Clone, Debug, Default)]
+pub struct DecBuilder {
+    streaming: bool,
+    output_format: Option<OutputFormat>,
+    planar: bool,
+    channel_selection: Vec<usize>,
+    channel_order: Option<ChannelOrder>,
+    force_stereo: bool,
+    max_pending: Option<usize>,
+    peak_metering: bool,
+    frame_pool: usize,
+    frame_flags: bool,
+    output_hash: bool,
+    frame_size: Option<usize>,
+    frame_provider: Option<SharedProvider>,
+    in_band_headers: bool,
+    header_resync: usize,
+    error_budget: Option<ErrorBudget>,
+    gapless: bool,
+    intra_only: bool,
+    laced_packets: bool,
+    retain_headers: bool,
+    extradata: Option<Vec<u8>>,
+}
+
+/// A `FrameProvider` shared by the clones of a `DecBuilder`.
+#[derive(Clone)]
+struct SharedProvider(Arc<Mutex<FrameProvider>>);
+
+impl fmt::Debug for SharedProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FrameProvider")
+    }
+}
+
+impl DecBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a decoder for live streams, see `Dec::new_streaming`.
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = Some(format);
+        self
+    }
+
+    pub fn planar(mut self, planar: bool) -> Self {
+        self.planar = planar;
+        self
+    }
+
+    pub fn channel_selection(mut self, chans: &[usize]) -> Self {
+        self.channel_selection = chans.to_vec();
+        self
+    }
+
+    pub fn output_channel_order(mut self, order: ChannelOrder) -> Self {
+        self.channel_order = Some(order);
+        self
+    }
+
+    pub fn force_stereo(mut self, enable: bool) -> Self {
+        self.force_stereo = enable;
+        self
+    }
+
+    pub fn max_pending(mut self, max: Option<usize>) -> Self {
+        self.max_pending = max;
+        self
+    }
+
+    pub fn peak_metering(mut self, enable: bool) -> Self {
+        self.peak_metering = enable;
+        self
+    }
+
+    pub fn frame_pool(mut self, size: usize) -> Self {
+        self.frame_pool = size;
+        self
+    }
+
+    pub fn frame_flags(mut self, enable: bool) -> Self {
+        self.frame_flags = enable;
+        self
+    }
+
+    pub fn output_hash(mut self, enable: bool) -> Self {
+        self.output_hash = enable;
+        self
+    }
+
+    pub fn frame_size(mut self, size: Option<usize>) -> Self {
+        self.frame_size = size;
+        self
+    }
+
+    /// Sets the frame provider of the decoder. The decoders built from the
+    /// clones of this builder share it.
+    pub fn frame_provider(mut self, provider: FrameProvider) -> Self {
+        self.frame_provider = Some(SharedProvider(Arc::new(Mutex::new(provider))));
+        self
+    }
+
+    pub fn in_band_headers(mut self, enable: bool) -> Self {
+        self.in_band_headers = enable;
+        self
+    }
+
+    pub fn header_resync(mut self, window: usize) -> Self {
+        self.header_resync = window;
+        self
+    }
+
+    pub fn error_budget(mut self, budget: Option<ErrorBudget>) -> Self {
+        self.error_budget = budget;
+        self
+    }
+
+    pub fn gapless(mut self, enable: bool) -> Self {
+        self.gapless = enable;
+        self
+    }
+
+    pub fn intra_only(mut self, enable: bool) -> Self {
+        self.intra_only = enable;
+        self
+    }
+
+    pub fn laced_packets(mut self, enable: bool) -> Self {
+        self.laced_packets = enable;
+        self
+    }
+
+    pub fn retain_headers(mut self, enable: bool) -> Self {
+        self.retain_headers = enable;
+        self
+    }
+
+    /// Sets the Matroska style extradata `build` configures the decoder
+    /// with.
+    pub fn extradata(mut self, extradata: &[u8]) -> Self {
+        self.extradata = Some(extradata.to_vec());
+        self
+    }
+
+    /// Creates the decoder, configured if extradata was given.
+    ///
+    /// Fails like `Dec::configure_from` if the extradata is rejected, or
+    /// with `Error::ConfigurationInvalid` if the channel selection does not
+    /// fit the stream, or if options conflict: a channel selection with an
+    /// output channel order, which the selection would override, forcing
+    /// stereo with a selection of several channels, which it would not
+    /// apply to, and a frame pool with a frame provider, whose buffers the
+    /// pool would hold on to.
+    pub fn build(self) -> Result<Dec> {
+        let selected = self.channel_selection.len();
+        if (selected > 0 && self.channel_order.is_some())
+            || (self.force_stereo && selected > 1)
+            || (self.frame_pool > 0 && self.frame_provider.is_some())
+        {
+            return Err(Error::ConfigurationInvalid);
+        }
+        let mut dec = if self.streaming {
+            Dec::new_streaming()
+        } else {
+            Dec::new()
+        };
+        if let Some(format) = self.output_format {
+            dec.set_output_format(format);
+        }
+        dec.set_planar(self.planar);
+        dec.set_channel_selection(&self.channel_selection)?;
+        if let Some(order) = self.channel_order {
+            dec.set_output_channel_order(order);
+        }
+        dec.set_force_stereo(self.force_stereo);
+        dec.set_max_pending(self.max_pending);
+        dec.set_peak_metering(self.peak_metering);
+        dec.set_frame_pool(self.frame_pool);
+        dec.set_frame_flags(self.frame_flags);
+        dec.set_output_hash(self.output_hash);
+        dec.set_frame_size(self.frame_size);
+        if let Some(SharedProvider(provider)) = self.frame_provider {
+            dec.set_frame_provider(Some(Box::new(move |info: &AudioInfo| {
+                (provider.lock().unwrap())(info)
+            })));
+        }
+        dec.set_in_band_headers(self.in_band_headers);
+        dec.set_header_resync(self.header_resync);
+        dec.set_error_budget(self.error_budget);
+        dec.set_gapless(self.gapless);
+        dec.set_intra_only(self.intra_only);
+        dec.set_laced_packets(self.laced_packets);
+        dec.set_retain_headers(self.retain_headers);
+        if let Some(ref extradata) = self.extradata {
+            dec.configure_from(extradata)?;
+        }
+        Ok(dec)
+    }
+}
+
+impl Des {
+    /// Lists the output formats `Dec::set_output_format` accepts, all but
+    /// `F64` available packed and planar as selected with `Dec::set_planar`.
+    pub fn supported_output_formats() -> &'static [OutputFormat] {
+        &[
+            OutputFormat::S16,
+            OutputFormat::S24,
+            OutputFormat::F32,
+            OutputFormat::F64,
+        ]
+    }
+}
+
+impl Descriptor for Des {
+    type OutputDecoder = Dec;
+
+    fn create(&self) -> Self::OutputDecoder {
+        Dec::new()
+    }
+
+    fn describe(&self) -> &Descr {
+        &self.descr
+    }
+}
+
+impl Decoder for Dec {
+    fn set_extradata(&mut self, extra: &[u8]) {
+        self.extradata = Some(Vec::from(extra));
+    }
+    fn send_packet(&mut self, pkt: &Packet) -> Result<()> {
+        self.decode(pkt, None)
+    }
+    fn receive_frame(&mut self) -> Result<ArcFrame> {
+        self.pending.pop_front().ok_or(Error::MoreDataNeeded)
+    }
+    fn configure(&mut self) -> Result<()> {
+        let extradata = match self.extradata.take() {
+            Some(extradata) => extradata,
+            None => return Err(Error::ConfigurationIncomplete),
+        };
+        let ret = self.configure_from(&extradata);
+        // Once parsed only the headers are needed, keep the bytes only
+        // when they were rejected
+        if ret.is_err() {
+            self.extradata = Some(extradata);
+        }
+        ret
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.truncation_pending = false;
+        self.truncated = false;
+        self.pwr = PreviousWindowRight::new();
+        self.last_head = None;
+        self.last_granule = None;
+        self.reset_peaks();
+        self.reset_bitrate();
+        self.reset_output_hash();
+        self.reset_cadence();
+        self.reset_error_budget();
+        Ok(())
+    }
+}
+
+/// Decodes a single audio packet with externally parsed headers, without
+/// setting up a `Dec`.
+///
+/// Returns the 16-bit samples of every channel, converted like the `S16`
+/// output of `Dec`. `pwr` carries the overlap between consecutive packets,
+/// the first packet after a fresh `PreviousWindowRight` yields no samples.
+pub fn decode_one(
+    headers: &HeaderSet,
+    pkt: &Packet,
+    pwr: &mut PreviousWindowRight,
+) -> Result<Vec<Vec<i16>>> {
+    read_audio_packet(&headers.0, &headers.2, pkt.data.as_slice(), pwr)
+        .map_err(|_e| Error::InvalidData)
+}
+
+/// Decodes a packet from a fresh overlap state, completing it with a silent
+/// packet to obtain the right half of its window.
+fn decode_intra(
+    headers: &(IdentHeader, CommentHeader, Arc<ParsedSetup>),
+    data: &[u8],
+) -> ::std::result::Result<Vec<Vec<f32>>, AudioReadError> {
+    let (ident, setup) = (&headers.0, &headers.2);
+    let mut pwr = PreviousWindowRight::new();
+    let empty = read_audio_packet_generic::<Vec<Vec<f32>>>(ident, &setup.header, data, &mut pwr)?;
+    match setup
+        .info
+        .as_ref()
+        .and_then(|info| silent_packet(info, packet_head(data), ident.audio_channels))
+    {
+        Some(tail) => read_audio_packet_generic(ident, &setup.header, &tail, &mut pwr),
+        None => Ok(empty),
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Joins a setup header packet with the identification header fields its
+/// parsing depends on.
+fn setup_key(packet: &[u8], channels: u8, blocksizes: (u8, u8)) -> Vec<u8> {
+    let mut key = vec![channels, blocksizes.0, blocksizes.1];
+    key.extend_from_slice(packet);
+    key
+}
+
+fn setup_hash(key: &[u8]) -> u64 {
+    key.iter().fold(FNV_OFFSET, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+const QUEUE_FULL: &str = "sending packets while the pending frame queue is full";
+
+const FRAME_ALLOC_FAILED: &str = "the frame buffer could not be allocated";
+
+/// Returns the number of samples per channel lewton returns for a block of
+/// the given size and window sizes, following a block whose right window
+/// is `prev`: the overlap with the previous block, then the block up to
+/// where its right window starts.
+fn overlap_len(prev: usize, (n, left, right): (usize, usize, usize)) -> usize {
+    prev / 2 + (3 * n / 4 - right / 4) - (n / 4 + left / 4)
+}
+
+/// Returns the first two bytes of a packet, zero padded, which is enough
+/// to tell its block size and windows.
+fn packet_head(data: &[u8]) -> [u8; 2] {
+    let mut head = [0; 2];
+    let len = data.len().min(2);
+    head[..len].copy_from_slice(&data[..len]);
+    head
+}
+
+fn frame_alloc_failed() -> Error {
+    Error::Unsupported(FRAME_ALLOC_FAILED.to_owned())
+}
+
+/// Checks that the buffer size `AudioInfo::size` computes for `info` can be
+/// represented.
+fn frame_size_fits(info: &AudioInfo) -> bool {
+    let sample_size = (info.format.bits as usize).div_ceil(8);
+    // The padding av-data adds to align the planes is much smaller than the
+    // headroom left
+    info.samples
+        .checked_mul(sample_size)
+        .and_then(|plane| plane.checked_mul(info.map.len()))
+        .is_some_and(|size| size <= isize::MAX as usize / 2)
+}
+
+/// Returns a stable category for an error returned by `Dec`, for logging
+/// and metrics.
+///
+/// The categories are `"configuration"` for missing or rejected headers,
+/// `"data"` for undecodable packets and an exhausted error budget,
+/// `"underflow"` when more input is needed, `"backpressure"` when the
+/// pending queue is full, `"allocation"` when no frame buffer could be
+/// obtained, and `"unsupported"` otherwise.
+pub fn error_category(err: &Error) -> &'static str {
+    match err {
+        Error::ConfigurationIncomplete | Error::ConfigurationInvalid => "configuration",
+        Error::InvalidData => "data",
+        Error::Unsupported(msg) if msg == ERROR_BUDGET_EXHAUSTED => "data",
+        Error::MoreDataNeeded => "underflow",
+        Error::Unsupported(msg) if msg == QUEUE_FULL => "backpressure",
+        Error::Unsupported(msg) if msg == FRAME_ALLOC_FAILED => "allocation",
+        Error::Unsupported(_) => "unsupported",
+    }
+}
+
+/// Returns the number of samples per channel of a frame, `AudioInfo`
+/// holding the count of all channels together for packed frames, and the
+/// count of each plane for planar ones.
+pub(crate) fn samples_per_channel(info: &AudioInfo) -> usize {
+    if info.format.planar {
+        info.samples
+    } else {
+        info.samples / info.map.len().max(1)
+    }
+}
+
+/// Sets the number of samples per channel of a frame, the inverse of
+/// `samples_per_channel`.
+///
+/// av-data allocates every plane of a planar frame for `samples`, so
+/// counting all channels there would make each plane as long as all of
+/// them together.
+fn set_samples_per_channel(info: &mut AudioInfo, len: usize) {
+    info.samples = if info.format.planar {
+        len
+    } else {
+        len * info.map.len()
+    };
+}
+
+/// Checks that a provided buffer can hold the samples of `info`.
+fn buffer_fits(buf: &dyn FrameBuffer, info: &AudioInfo) -> bool {
+    let channels = info.map.len();
+    let sample_size = (info.format.bits as usize).div_ceil(8);
+    let (planes, plane_size) = if info.format.planar {
+        (channels, info.samples * sample_size)
+    } else {
+        (1, info.samples * sample_size)
+    };
+    buf.count() >= planes
+        && (0..planes).all(|i| {
+            buf.as_slice_inner(i)
+                .is_ok_and(|plane| plane.len() >= plane_size)
+        })
+}
+
+/// The alignment `Frame::new_default_frame` gives the frame buffers.
+#[cfg(debug_assertions)]
+const FRAME_ALIGNMENT: usize = 32;
+
+/// Checks a frame decoded by `Dec` against the `AudioInfo` it is expected
+/// to have, as a debugging aid for code handling the frames. Only built
+/// with debug assertions.
+///
+/// Fails with `Error::InvalidData` if the frame is not an audio frame of
+/// that sample rate, channel map, sample format and number of samples, or
+/// if its buffer is not exactly the one `Frame::new_default_frame` lays
+/// out for them. Buffers of a frame provider are only valid if sized the
+/// same way.
+#[cfg(debug_assertions)]
+pub fn validate_frame(frame: &Frame, info: &AudioInfo) -> Result<()> {
+    let frame_info = match frame.kind {
+        MediaKind::Audio(ref frame_info) => frame_info,
+        MediaKind::Video(_) => return Err(Error::InvalidData),
+    };
+    let matches = frame_info.samples == info.samples
+        && frame_info.sample_rate == info.sample_rate
+        && frame_info.map == info.map
+        && *frame_info.format == *info.format;
+    if !matches || info.map.is_empty() {
+        return Err(Error::InvalidData);
+    }
+    let (planes, plane_size) = if info.format.planar {
+        let size = info.format.get_audio_size(info.samples, FRAME_ALIGNMENT);
+        (info.map.len(), size)
+    } else {
+        (1, info.size(FRAME_ALIGNMENT))
+    };
+    let exact = frame.buf.count() == planes
+        && (0..planes).all(|i| {
+            frame
+                .buf
+                .as_slice_inner(i)
+                .is_ok_and(|plane| plane.len() == plane_size)
+        });
+    if !exact {
+        return Err(Error::InvalidData);
+    }
+    Ok(())
+}
+
+const ERROR_BUDGET_EXHAUSTED: &str = "too many undecodable packets for the error budget";
+
+/// Returns whether `err` is the terminal error `send_packet` returns once
+/// the budget set with `Dec::set_error_budget` is exhausted.
+pub fn is_error_budget_exhausted(err: &Error) -> bool {
+    match err {
+        Error::Unsupported(msg) => msg == ERROR_BUDGET_EXHAUSTED,
+        _ => false,
+    }
+}
+
+/// Returns whether `err` is the backpressure error `send_packet` returns
+/// when the limit set with `Dec::set_max_pending` is reached.
+///
+/// The packet was not consumed and should be sent again once some frames
+/// have been received.
+pub fn is_queue_full(err: &Error) -> bool {
+    match err {
+        Error::Unsupported(msg) => msg == QUEUE_FULL,
+        _ => false,
+    }
+}
+
+/// Builds the channel layout of a stream, following the channel order
+/// defined in section 4.3.9 of the Vorbis I specification.
+///
+/// The specification leaves the order of streams with more than eight
+/// channels to the application, so they are passed through in stream
+/// order, each channel labelled `C` as there is no position to report.
+fn vorbis_channel_map(count: usize) -> ChannelMap {
+    use self::ChannelType::*;
+    let ids: &[ChannelType] = match count {
+        1 => &[C],
+        2 => &[L, R],
+        3 => &[L, C, R],
+        4 => &[L, R, Ls, Rs],
+        5 => &[L, C, R, Ls, Rs],
+        6 => &[L, C, R, Ls, Rs, LFE],
+        7 => &[L, C, R, Lss, Rss, Cs, LFE],
+        8 => &[L, C, R, Lss, Rss, Ls, Rs, LFE],
+        _ => {
+            let mut map = ChannelMap::new();
+            for _ in 0..count {
+                map.add_channel(C);
+            }
+            return map;
+        }
+    };
+    let mut map = ChannelMap::new();
+    map.add_channels(ids);
+    map
+}
+
+/// Returns the stream channels to output for a selection and an order,
+/// `None` if that is all channels in stream order.
+fn output_channels(
+    map: &ChannelMap,
+    selection: Option<&Vec<usize>>,
+    order: ChannelOrder,
+) -> Option<Vec<usize>> {
+    use self::ChannelType::*;
+    if let Some(selection) = selection {
+        return Some(selection.clone());
+    }
+    // Ls and Rs are the rear channels in the Vorbis layouts, Lss and Rss
+    // the side channels
+    let ranks: &[ChannelType] = match order {
+        ChannelOrder::Vorbis => return None,
+        ChannelOrder::WaveExtensible => &[L, R, C, LFE, Ls, Rs, Cs, Lss, Rss],
+        ChannelOrder::Smpte => &[L, R, C, LFE, Lss, Rss, Ls, Rs, Cs],
+    };
+    let rank = |c: usize| {
+        let id = map.get_channel(c);
+        ranks.iter().position(|&r| r == id).unwrap_or(ranks.len())
+    };
+    let mut channels: Vec<usize> = (0..map.len()).collect();
+    // Stable, unknown positions keep their stream order at the end
+    channels.sort_by_key(|&c| rank(c));
+    if channels.iter().enumerate().all(|(i, &c)| i == c) {
+        None
+    } else {
+        Some(channels)
+    }
+}
+
+/// Returns the output layout for a channel selection, rejecting
+/// out of range and repeated channels.
+fn select_channels(map: &ChannelMap, selection: Option<&Vec<usize>>) -> Result<ChannelMap> {
+    let selection = match selection {
+        Some(selection) => selection,
+        None => return Ok(map.clone()),
+    };
+    let mut out = ChannelMap::new();
+    for (i, &c) in selection.iter().enumerate() {
+        if c >= map.len() || selection[..i].contains(&c) {
+            return Err(Error::ConfigurationInvalid);
+        }
+        out.add_channel(map.get_channel(c));
+    }
+    Ok(out)
+}
+
+/// Trims the samples of a packet ending at `granule`, with `position`
+/// samples per channel emitted before it, `end` if it is the last packet
+/// of the stream. Returns whether anything was removed.
+fn trim_to_granule(samples: &mut [Vec<f32>], position: u64, granule: u64, end: bool) -> bool {
+    let len = samples[0].len() as u64;
+    if position == 0 && len > granule && !end {
+        let surplus = (len - granule) as usize;
+        for chan in samples.iter_mut() {
+            chan.drain(..surplus);
+        }
+        true
+    } else {
+        truncate_at(samples, position, granule)
+    }
+}
+
+/// Drops the samples of a packet starting at `position` that lie beyond
+/// `end`. Returns whether anything was removed.
+fn truncate_at(samples: &mut [Vec<f32>], position: u64, end: u64) -> bool {
+    if position + samples[0].len() as u64 <= end {
+        return false;
+    }
+    let keep = end.saturating_sub(position) as usize;
+    for chan in samples.iter_mut() {
+        chan.truncate(keep);
+    }
+    true
+}
+
+fn update_peaks(peaks: &mut Vec<f32>, samples: &[Vec<f32>]) {
+    peaks.resize(samples.len(), 0.0);
+    for (peak, chan) in peaks.iter_mut().zip(samples) {
+        for v in chan {
+            *peak = peak.max(v.abs());
+        }
+    }
+}
+
+/// A sample type the decoded floats can be converted to.
+trait OutputSample: Copy {
+    /// Size of one sample in bytes.
+    const SIZE: usize;
+
+    fn from_float(fl: f32) -> Self;
+    fn write_le(self, dst: &mut [u8]);
+}
+
+impl OutputSample for i16 {
+    const SIZE: usize = 2;
+
+    // Same conversion lewton performs for its own i16 output
+    fn from_float(fl: f32) -> Self {
+        let fl = fl * 32768.0;
+        if fl > 32767. {
+            32767
+        } else if fl < -32768. {
+            -32768
+        } else {
+            fl as i16
+        }
+    }
+    fn write_le(self, dst: &mut [u8]) {
+        dst.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// A 24-bit sample held in the high bits of an `i32`.
+#[derive(Clone, Copy)]
+struct S24(i32);
+
+impl OutputSample for S24 {
+    const SIZE: usize = 4;
+
+    fn from_float(fl: f32) -> Self {
+        const MAX: f32 = 8_388_607.0;
+        const MIN: f32 = -8_388_608.0;
+        let fl = fl * 8_388_608.0;
+        let v = if fl > MAX {
+            MAX as i32
+        } else if fl < MIN {
+            MIN as i32
+        } else {
+            fl as i32
+        };
+        S24(v << 8)
+    }
+    fn write_le(self, dst: &mut [u8]) {
+        dst.copy_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl OutputSample for f32 {
+    const SIZE: usize = 4;
+
+    fn from_float(fl: f32) -> Self {
+        fl
+    }
+    fn write_le(self, dst: &mut [u8]) {
+        dst.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl OutputSample for f64 {
+    const SIZE: usize = 8;
+
+    fn from_float(fl: f32) -> Self {
+        f64::from(fl)
+    }
+    fn write_le(self, dst: &mut [u8]) {
+        dst.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// Stores the samples in the planes of `frame`, interleaved in the first
+/// plane or one channel per plane.
+fn write_samples<S: OutputSample>(frame: &mut Frame, samples: &[Vec<f32>], planar: bool) {
+    if planar {
+        for (i, chan) in samples.iter().enumerate() {
+            let buf = frame.buf.as_mut_slice_inner(i).unwrap();
+            interleave::<S>(buf, std::slice::from_ref(chan));
+        }
+    } else {
+        interleave::<S>(frame.buf.as_mut_slice_inner(0).unwrap(), samples);
+    }
+}
+
+/// Converts the per-channel decoded samples to `S` and packs them
+/// interleaved into `buf`.
+fn interleave<S: OutputSample>(buf: &mut [u8], samples: &[Vec<f32>]) {
+    let channel_count = samples.len();
+    let sample_count = samples[0].len();
+    for i in 0..sample_count {
+        for (cn, chan) in samples.iter().enumerate() {
+            let off = (i * channel_count + cn) * S::SIZE;
+            S::from_float(chan[i]).write_le(&mut buf[off..off + S::SIZE]);
+        }
+    }
+}
+
+type HeaderPackets<'a> = (&'a [u8], &'a [u8], &'a [u8]);
+
+/// Splits Matroska style extradata into the identification, comment and
+/// setup header packets, checking the packet type and the "vorbis" magic
+/// of each.
+fn split_headers(mut extradata: &[u8]) -> ::std::result::Result<HeaderPackets<'_>, &'static str> {
+    // We must start with a 2 as per matroska encapsulation spec
+    if extradata.is_empty() || extradata[0] != 2 {
+        return Err("extradata does not hold three laced headers");
+    }
+    extradata = &extradata[1..];
+    let ident_len = read_xiph_lacing(&mut extradata).map_err(|_e| "truncated lacing")? as usize;
+    let comment_len = read_xiph_lacing(&mut extradata).map_err(|_e| "truncated lacing")? as usize;
+    if ident_len.saturating_add(comment_len) > extradata.len() {
+        return Err("header lengths exceed the extradata");
+    }
+    // A lacing value of 0 is well-formed, but no header is empty
+    if ident_len == 0 {
+        return Err("empty identification header");
+    }
+    if comment_len == 0 {
+        return Err("empty comment header");
+    }
+    let (ident, rest) = extradata.split_at(ident_len);
+    let (comment, setup) = rest.split_at(comment_len);
+    if setup.is_empty() {
+        return Err("empty setup header");
+    }
+    if !has_magic(ident, 1) {
+        return Err("bad identification header magic");
+    }
+    if !has_magic(comment, 3) {
+        return Err("bad comment header magic");
+    }
+    if !has_magic(setup, 5) {
+        return Err("bad setup header magic");
+    }
+    Ok((ident, comment, setup))
+}
+
+/// Checks the packet type and the capture pattern of a header packet.
+fn has_magic(header: &[u8], packet_type: u8) -> bool {
+    header.len() >= 7 && header[0] == packet_type && &header[1..7] == b"vorbis"
+}
+
+/// Finds an identification header starting within the first `window`
+/// bytes past the start of `data`.
+fn find_ident(data: &[u8], window: usize) -> Option<usize> {
+    (0..=window.min(data.len())).find(|&offset| has_magic(&data[offset..], 1))
+}
+
+fn read_xiph_lacing(arr: &mut &[u8]) -> Result<u64> {
+    let mut r = 0;
+    loop {
+        if arr.is_empty() {
+            return Err(Error::InvalidData);
+        }
+        let v = arr[0] as u64;
+        *arr = &arr[1..];
+        r += v;
+        if v < 255 {
+            return Ok(r);
+        }
+    }
+}
+
+/// Splits a Xiph laced container packet into the packets it holds, `None`
+/// if the sizes do not fit.
+fn split_laced(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let (&count, rest) = data.split_first()?;
+    data = rest;
+    let mut sizes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        sizes.push(read_xiph_lacing(&mut data).ok()? as usize);
+    }
+    let mut packets = Vec::with_capacity(count as usize + 1);
+    for size in sizes {
+        if size > data.len() {
+            return None;
+        }
+        let (packet, rest) = data.split_at(size);
+        packets.push(packet);
+        data = rest;
+    }
+    packets.push(data);
+    Some(packets)
+}
+
+fn write_xiph_lacing(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+pub const VORBIS_DESCR: &Des = &Des {
     descr: Descr {
         codec: "vorbis",
         name: "lewton",
@@ -152,3 +2498,1769 @@ pub const VORBIS_DESCR: &Des = &Des {
         mime: "audio/VORBIS",
     },
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture;
+
+    /// Floor amplitudes of a loud and a quiet channel.
+    const TONE: &[u8] = &[255, 200];
+
+    /// Floor amplitudes of six channels, each at its own level.
+    const SURROUND: &[u8] = &[255, 250, 245, 240, 235, 230];
+
+    fn receive_all(dec: &mut Dec) -> Vec<ArcFrame> {
+        let mut frames = Vec::new();
+        while let Ok(f) = dec.receive_frame() {
+            frames.push(f);
+        }
+        frames
+    }
+
+    /// Sends every packet, then ends the stream, and returns all frames.
+    fn decode(dec: &mut Dec, packets: &[Vec<u8>]) -> Vec<ArcFrame> {
+        let mut frames = Vec::new();
+        for data in packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+            frames.extend(receive_all(dec));
+        }
+        dec.send_eof().unwrap();
+        frames.extend(receive_all(dec));
+        frames
+    }
+
+    fn pcm_i16(frames: &[ArcFrame]) -> Vec<i16> {
+        frames
+            .iter()
+            .flat_map(|f| frame_to_le_bytes(f))
+            .collect::<Vec<u8>>()
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    fn pcm_i32(frames: &[ArcFrame]) -> Vec<i32> {
+        frames
+            .iter()
+            .flat_map(|f| frame_to_le_bytes(f))
+            .collect::<Vec<u8>>()
+            .chunks(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    fn pcm_f64(frames: &[ArcFrame]) -> Vec<f64> {
+        frames
+            .iter()
+            .flat_map(|f| frame_to_le_bytes(f))
+            .collect::<Vec<u8>>()
+            .chunks(8)
+            .map(|b| {
+                let mut v = [0; 8];
+                v.copy_from_slice(b);
+                f64::from_le_bytes(v)
+            })
+            .collect()
+    }
+
+    /// Extracts one channel of interleaved samples.
+    fn channel<T: Copy>(pcm: &[T], channels: usize, c: usize) -> Vec<T> {
+        pcm.iter().skip(c).step_by(channels).cloned().collect()
+    }
+
+    fn audio_info(f: &Frame) -> &AudioInfo {
+        match f.kind {
+            MediaKind::Audio(ref info) => info,
+            MediaKind::Video(_) => panic!("video frame"),
+        }
+    }
+
+    #[test]
+    fn s24_is_left_justified_s32() {
+        let packets = fixture::audio_packets(&[true, true, false, true], TONE);
+        let s16 = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        let mut dec = fixture::decoder(2);
+        dec.set_output_format(OutputFormat::S24);
+        let frames = decode(&mut dec, &packets);
+        assert!(frames.iter().all(|f| *audio_info(f).format == S32));
+        let s24 = pcm_i32(&frames);
+        assert_eq!(s24.len(), s16.len());
+        assert!(s24.iter().all(|v| v & 0xff == 0));
+        // The loud channel peaks close to full scale
+        let peak = s24.iter().map(|v| v.unsigned_abs()).max().unwrap();
+        assert!(peak > 1 << 30, "peak {}", peak);
+        for (&a, &b) in s16.iter().zip(&s24) {
+            assert!(((b >> 16) - i32::from(a)).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn chained_rate_change_is_reported() {
+        let packets = fixture::audio_packets(&[true, true], TONE);
+        let mut dec = Dec::new();
+        dec.configure_from(&fixture::extradata(2, 44100)).unwrap();
+        assert!(!dec.took_new_config());
+        decode(&mut dec, &packets);
+        dec.configure_from(&fixture::extradata(2, 44100)).unwrap();
+        assert!(!dec.took_new_config());
+        dec.configure_from(&fixture::extradata(2, 48000)).unwrap();
+        assert!(dec.took_new_config());
+        assert!(!dec.took_new_config());
+        let frames = decode(&mut dec, &packets);
+        assert!(!frames.is_empty());
+        assert!(frames.iter().all(|f| audio_info(f).sample_rate == 48000));
+    }
+
+    #[test]
+    fn full_queue_rejects_packets_until_drained() {
+        let packets = fixture::audio_packets(&[true; 5], TONE);
+        let mut dec = fixture::decoder(2);
+        dec.set_max_pending(Some(2));
+        for data in &packets[..3] {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        assert_eq!(dec.pending_frames(), 2);
+        let err = dec.send_packet(&fixture::packet(&packets[3])).unwrap_err();
+        assert!(is_queue_full(&err));
+        assert_eq!(error_category(&err), "backpressure");
+        assert_eq!(dec.pending_frames(), 2);
+        // The rejected packet was not consumed and goes through once there
+        // is room again
+        dec.receive_frame().unwrap();
+        dec.send_packet(&fixture::packet(&packets[3])).unwrap();
+        assert_eq!(dec.pending_frames(), 2);
+        assert!(is_queue_full(
+            &dec.send_packet(&fixture::packet(&packets[4])).unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn full_queue_accounts_for_fixed_frame_size() {
+        let packets = fixture::audio_packets(&[true; 6], TONE);
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_size(Some(512));
+        dec.set_max_pending(Some(4));
+        let mut sent = 0;
+        let mut received = 0;
+        while sent < packets.len() {
+            match dec.send_packet(&fixture::packet(&packets[sent])) {
+                Ok(()) => sent += 1,
+                Err(ref e) if is_queue_full(e) => {
+                    dec.receive_frame().unwrap();
+                    received += 1;
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+            assert!(dec.pending_frames() <= 4);
+        }
+        received += receive_all(&mut dec).len();
+        assert_eq!(received, 5 * 1024 / 512);
+    }
+
+    #[test]
+    fn peaks_match_the_decoded_maximum() {
+        let packets = fixture::audio_packets(&[true, false, true], TONE);
+        let mut dec = fixture::decoder(2);
+        dec.set_output_format(OutputFormat::F64);
+        dec.set_peak_metering(true);
+        assert!(dec.channel_peaks().is_empty());
+        let pcm = pcm_f64(&decode(&mut dec, &packets));
+        let mut expected = [0.0f64; 2];
+        for (i, v) in pcm.iter().enumerate() {
+            expected[i % 2] = expected[i % 2].max(v.abs());
+        }
+        let peaks = dec.channel_peaks();
+        assert_eq!(peaks.len(), 2);
+        assert_eq!(f64::from(peaks[0]), expected[0]);
+        assert_eq!(f64::from(peaks[1]), expected[1]);
+        assert!(peaks[0] > 0.9 && peaks[1] < peaks[0] / 10.0);
+        dec.reset_peaks();
+        assert!(dec.channel_peaks().is_empty());
+    }
+
+    #[test]
+    fn channel_selection_reduces_and_reorders_the_output() {
+        use self::ChannelType::*;
+        let packets = fixture::audio_packets(&[true, true, false], SURROUND);
+        let full = pcm_i16(&decode(&mut fixture::decoder(6), &packets));
+        let mut dec = fixture::decoder(6);
+        dec.set_channel_selection(&[4, 1]).unwrap();
+        let frames = decode(&mut dec, &packets);
+        let map = &audio_info(&frames[0]).map;
+        assert_eq!(map.len(), 2);
+        assert_eq!((map.get_channel(0), map.get_channel(1)), (Rs, C));
+        let pcm = pcm_i16(&frames);
+        assert_eq!(channel(&pcm, 2, 0), channel(&full, 6, 4));
+        assert_eq!(channel(&pcm, 2, 1), channel(&full, 6, 1));
+        assert!(matches!(
+            dec.set_channel_selection(&[6]),
+            Err(Error::ConfigurationInvalid)
+        ));
+        assert!(matches!(
+            dec.set_channel_selection(&[1, 1]),
+            Err(Error::ConfigurationInvalid)
+        ));
+    }
+
+    #[test]
+    fn frame_pool_reuses_dropped_frames() {
+        let packets = fixture::audio_packets(&[true; 6], TONE);
+        let expected = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_pool(2);
+        let mut pcm = Vec::new();
+        let mut buffers = Vec::new();
+        for data in &packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+            for f in receive_all(&mut dec) {
+                buffers.push(f.buf.as_slice_inner(0).unwrap().as_ptr() as usize);
+                pcm.extend(pcm_i16(&[f]));
+            }
+        }
+        assert_eq!(buffers.len(), 5);
+        // Every frame was dropped before the next was decoded
+        assert!(buffers.iter().all(|&b| b == buffers[0]));
+        dec.send_eof().unwrap();
+        pcm.extend(pcm_i16(&receive_all(&mut dec)));
+        assert_eq!(pcm, expected);
+    }
+
+    #[test]
+    fn bitrate_follows_the_packet_sizes() {
+        let packets = fixture::audio_packets(&[true; 80], TONE);
+        let bytes = packets[1].len() as u64;
+        let mut dec = fixture::decoder(2);
+        assert_eq!(dec.instantaneous_bitrate(), None);
+        for data in &packets[..10] {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        // The first packet counts without decoding any samples
+        let expected = 10 * bytes * 8 * 48000 / (9 * 1024);
+        assert_eq!(dec.instantaneous_bitrate(), Some(expected as u32));
+        for data in &packets[10..] {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        // Once the window spans a second, about one packet per 1024 samples
+        let steady = (bytes * 8 * 48000 / 1024) as u32;
+        let bitrate = dec.instantaneous_bitrate().unwrap();
+        assert!(bitrate >= steady && bitrate < steady + steady / 20);
+        dec.flush().unwrap();
+        assert_eq!(dec.instantaneous_bitrate(), None);
+    }
+
+    fn total_len(frames: &[ArcFrame]) -> usize {
+        frames
+            .iter()
+            .map(|f| samples_per_channel(audio_info(f)))
+            .sum()
+    }
+
+    fn decode_with_granules(dec: &mut Dec, packets: &[Vec<u8>], granules: &[u64]) -> Vec<ArcFrame> {
+        let mut frames = Vec::new();
+        for (data, &granule) in packets.iter().zip(granules) {
+            dec.send_packet_with_granule(&fixture::packet(data), granule)
+                .unwrap();
+            frames.extend(receive_all(dec));
+        }
+        dec.send_eof().unwrap();
+        frames.extend(receive_all(dec));
+        frames
+    }
+
+    #[test]
+    fn granules_trim_the_stream_to_its_length() {
+        let packets = fixture::audio_packets(&[true, true, false, true, true], TONE);
+        let mut granules = fixture::granules(&packets);
+        let full = *granules.last().unwrap();
+        *granules.last_mut().unwrap() -= 100;
+        let frames = decode_with_granules(&mut fixture::decoder(2), &packets, &granules);
+        assert_eq!(total_len(&frames) as u64, full - 100);
+        // The trimmed samples are the last ones
+        let untrimmed = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        let pcm = pcm_i16(&frames);
+        assert_eq!(pcm[..], untrimmed[..pcm.len()]);
+    }
+
+    #[test]
+    fn granules_trim_the_start_of_a_late_stream() {
+        let packets = fixture::audio_packets(&[true, true, true], TONE);
+        let granules: Vec<u64> = fixture::granules(&packets)
+            .iter()
+            .map(|g| g.saturating_sub(300))
+            .collect();
+        let frames = decode_with_granules(&mut fixture::decoder(2), &packets, &granules);
+        assert_eq!(samples_per_channel(audio_info(&frames[0])), 1024 - 300);
+        assert_eq!(total_len(&frames) as u64, granules[2]);
+        let untrimmed = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        let pcm = pcm_i16(&frames);
+        assert_eq!(pcm[..], untrimmed[2 * 300..2 * 300 + pcm.len()]);
+    }
+
+    #[test]
+    fn granule_of_a_first_and_last_packet_trims_its_end() {
+        let packets = fixture::audio_packets(&[true, true], TONE);
+        let len = fixture::decoded_lengths(&packets)[1] as u64;
+        let untrimmed = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        let mut dec = fixture::decoder(2);
+        dec.send_packet_with_granule(&fixture::packet(&packets[0]), 0)
+            .unwrap();
+        dec.send_last_packet_with_granule(&fixture::packet(&packets[1]), len - 100)
+            .unwrap();
+        dec.send_eof().unwrap();
+        let frames = receive_all(&mut dec);
+        assert_eq!(total_len(&frames) as u64, len - 100);
+        let pcm = pcm_i16(&frames);
+        assert_eq!(pcm[..], untrimmed[..pcm.len()]);
+    }
+
+    fn priming(frames: &[ArcFrame]) -> Vec<bool> {
+        frames
+            .iter()
+            .map(|f| frame_flags(f).unwrap().priming)
+            .collect()
+    }
+
+    #[test]
+    fn first_frame_after_flush_is_flagged_priming() {
+        let packets = fixture::audio_packets(&[true, false, true, true], TONE);
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_flags(true);
+        let frames = decode(&mut dec, &packets);
+        assert_eq!(priming(&frames), [true, false, false, false]);
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        dec.flush().unwrap();
+        let frames = decode(&mut dec, &packets[1..]);
+        assert_eq!(priming(&frames), [true, false, false]);
+    }
+
+    #[test]
+    fn only_file_mode_drains_the_tail() {
+        let packets = fixture::audio_packets(&[true, false, true], TONE);
+        let body: usize = fixture::decoded_lengths(&packets).iter().sum();
+        let tail = fixture::tail_len(&packets[2]);
+        let frames = decode(&mut fixture::decoder(2), &packets);
+        assert_eq!(total_len(&frames), body + tail);
+        // The tail frame follows the decoded samples
+        let last = frames.last().unwrap();
+        assert_eq!(last.t.pts, Some(body as i64));
+        assert_eq!(samples_per_channel(audio_info(last)), tail);
+        let mut dec = fixture::configure(Dec::new_streaming(), 2);
+        assert_eq!(total_len(&decode(&mut dec, &packets)), body);
+    }
+
+    #[test]
+    fn tail_is_the_overlap_faded_out() {
+        // The window shapes of the last block and of the block of silence
+        // completing it
+        let endings = [
+            ((true, true), (true, true), 1024),
+            ((true, false), (false, false), 128),
+            ((false, false), (false, false), 128),
+        ];
+        for &((long, next), (silence_long, silence_next), len) in endings.iter() {
+            let first = fixture::audio_packet(true, false, long, TONE);
+            let last = fixture::audio_packet(long, true, next, TONE);
+            assert_eq!(fixture::tail_len(&last), len);
+            let packets = vec![first, last];
+            let frames = decode(&mut fixture::decoder(2), &packets);
+            let tail = &frames[frames.len() - 1..];
+            assert_eq!(total_len(tail), len);
+            // The same as decoding a block leaving every channel unused
+            let silence = fixture::audio_packet(silence_long, true, silence_next, &[0, 0]);
+            let mut dec = fixture::configure(Dec::new_streaming(), 2);
+            let frames = decode(&mut dec, &[packets[0].clone(), packets[1].clone(), silence]);
+            assert_eq!(pcm_i16(tail), pcm_i16(&frames[frames.len() - 1..]));
+        }
+    }
+
+    #[test]
+    fn tail_is_not_profiled_or_counted_in_the_bitrate() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let packets = fixture::audio_packets(&[true; 4], TONE);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut dec = fixture::decoder(2);
+        dec.set_profiling_hook(Some(Box::new(move |_stats| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })));
+        for data in &packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        let bitrate = dec.instantaneous_bitrate();
+        dec.send_eof().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), packets.len());
+        assert_eq!(dec.instantaneous_bitrate(), bitrate);
+        assert_eq!(receive_all(&mut dec).len(), 4);
+    }
+
+    #[test]
+    fn tail_rejected_by_a_full_queue_can_be_drained_again() {
+        let packets = fixture::audio_packets(&[true; 3], TONE);
+        let mut dec = fixture::decoder(2);
+        dec.set_max_pending(Some(1));
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        dec.send_packet(&fixture::packet(&packets[1])).unwrap();
+        assert!(is_queue_full(&dec.send_eof().unwrap_err()));
+        dec.receive_frame().unwrap();
+        dec.send_eof().unwrap();
+        assert_eq!(total_len(&receive_all(&mut dec)), 1024);
+        // Drained for good
+        dec.send_eof().unwrap();
+        assert_eq!(dec.pending_frames(), 0);
+    }
+
+    #[test]
+    fn total_samples_trims_over_long_decodes_only() {
+        // The final short block decodes less than the long ones before it
+        let packets = fixture::audio_packets(&[true, true, true, false], TONE);
+        let decoded = fixture::decoded_lengths(&packets).iter().sum::<usize>()
+            + fixture::tail_len(&packets[3]);
+        for &total in &[decoded - 1300, decoded - 50, decoded, decoded + 1000] {
+            let mut dec = fixture::decoder(2);
+            dec.set_total_samples(Some(total as u64));
+            let frames = decode(&mut dec, &packets);
+            assert_eq!(total_len(&frames), total.min(decoded));
+        }
+    }
+
+    #[test]
+    fn seek_reset_resumes_at_the_new_position() {
+        let packets = fixture::audio_packets(&[true, false, true, true, false, true, true], TONE);
+        let granules = fixture::granules(&packets);
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_flags(true);
+        let reference = decode(&mut dec, &packets);
+        for data in &packets[..3] {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        dec.seek_reset(granules[3]);
+        assert_eq!(dec.pending_frames(), 0);
+        assert_eq!(dec.samples_decoded(), granules[3]);
+        let mut frames = Vec::new();
+        for (i, data) in packets.iter().enumerate().skip(3) {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+            frames.extend(receive_all(&mut dec));
+            assert_eq!(dec.samples_decoded(), granules[i]);
+        }
+        // The packet decoded first only primes the overlap, after it the
+        // output is the same as without the seek
+        assert_eq!(frames.len(), 3);
+        assert_eq!(priming(&frames), [true, false, false]);
+        assert_eq!(pcm_i16(&frames), pcm_i16(&reference[3..6]));
+    }
+
+    #[test]
+    fn bad_magic_names_the_header() {
+        let errors = [
+            "bad identification header magic",
+            "bad comment header magic",
+            "bad setup header magic",
+        ];
+        for (i, &error) in errors.iter().enumerate() {
+            for &(pos, byte) in &[(3, b'X'), (0, 7)] {
+                let mut headers = fixture::headers(2, 48000);
+                headers[i][pos] = byte;
+                let mut dec = Dec::new();
+                let ret = dec.configure_from(&fixture::lace_headers(&headers));
+                assert!(matches!(ret, Err(Error::InvalidData)));
+                assert_eq!(dec.config_error(), Some(error));
+            }
+        }
+        let mut dec = Dec::new();
+        dec.configure_from(&fixture::extradata(2, 48000)).unwrap();
+        assert_eq!(dec.config_error(), None);
+    }
+
+    #[test]
+    fn configure_keeps_no_extradata_copy() {
+        let mut dec = Dec::new();
+        dec.set_extradata(&fixture::extradata(2, 48000));
+        dec.configure().unwrap();
+        assert!(dec.extradata.is_none());
+        assert!(dec.header_data.is_none());
+        let mut bad = fixture::extradata(2, 48000);
+        bad.truncate(40);
+        dec.set_extradata(&bad);
+        assert!(dec.configure().is_err());
+        assert_eq!(dec.extradata.as_ref(), Some(&bad));
+    }
+
+    #[test]
+    fn sample_timestamp_round_trip() {
+        assert!(Dec::new().sample_to_timestamp(0).is_none());
+        let dec = fixture::decoder(2);
+        for &sample in &[0, 1, 1023, 48000, 1 << 40] {
+            let t = dec.sample_to_timestamp(sample).unwrap();
+            assert_eq!(t.timebase, Some(Rational64::new(1, 48000)));
+            assert_eq!(dec.timestamp_to_sample(&t), Some(sample));
+        }
+        let ms = |pts| TimeInfo {
+            pts: Some(pts),
+            timebase: Some(Rational64::new(1, 1000)),
+            ..Default::default()
+        };
+        assert_eq!(dec.timestamp_to_sample(&ms(1500)), Some(72000));
+        assert_eq!(dec.timestamp_to_sample(&ms(-1)), None);
+        assert_eq!(dec.timestamp_to_sample(&TimeInfo::default()), None);
+    }
+
+    #[test]
+    fn nonzero_vorbis_version_is_rejected() {
+        let mut headers = fixture::headers(2, 48000);
+        headers[0][7] = 1;
+        let mut dec = Dec::new();
+        let ret = dec.configure_from(&fixture::lace_headers(&headers));
+        assert!(matches!(ret, Err(Error::InvalidData)));
+        assert_eq!(
+            dec.config_error(),
+            Some("unsupported vorbis version, only version 0 is defined")
+        );
+        headers[0].truncate(9);
+        assert!(dec
+            .configure_from(&fixture::lace_headers(&headers))
+            .is_err());
+        assert_eq!(dec.config_error(), Some("truncated identification header"));
+    }
+
+    #[test]
+    fn planar_frames_hold_one_channel_per_plane() {
+        let packets = fixture::audio_packets(&[true, false, true], TONE);
+        let packed = decode(&mut fixture::decoder(2), &packets);
+        let mut dec = fixture::decoder(2);
+        dec.set_planar(true);
+        for data in &packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        assert_eq!(
+            dec.pending_samples(),
+            fixture::decoded_lengths(&packets).iter().sum::<usize>()
+        );
+        dec.send_eof().unwrap();
+        let planar = receive_all(&mut dec);
+        assert_eq!(planar.len(), packed.len());
+        for (p, f) in packed.iter().zip(&planar) {
+            let len = samples_per_channel(audio_info(p));
+            let info = audio_info(f);
+            assert!(info.format.planar);
+            assert_eq!(info.samples, len);
+            assert_eq!(samples_per_channel(info), len);
+            assert_eq!(f.buf.count(), 2);
+            let pcm = pcm_i16(std::slice::from_ref(p));
+            for c in 0..2 {
+                // Only padded to the alignment, not sized for both channels
+                let plane = f.buf.as_slice_inner(c).unwrap();
+                assert!(plane.len() >= len * 2 && plane.len() < len * 2 + 64);
+                let samples: Vec<i16> = plane[..len * 2]
+                    .chunks(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+                assert_eq!(samples, channel(&pcm, 2, c));
+            }
+        }
+        assert_eq!(pcm_i16(&planar), pcm_i16(&packed));
+    }
+
+    #[test]
+    fn output_hash_is_deterministic() {
+        let hash = |amps: &[u8]| {
+            let mut dec = fixture::decoder(2);
+            dec.set_output_hash(true);
+            decode(
+                &mut dec,
+                &fixture::audio_packets(&[true, false, true], amps),
+            );
+            dec.output_hash()
+        };
+        assert_eq!(hash(TONE), hash(TONE));
+        assert_ne!(hash(TONE), hash(&[255, 199]));
+        assert_ne!(hash(TONE), FNV_OFFSET);
+        let mut dec = fixture::decoder(2);
+        assert_eq!(dec.output_hash(), 0);
+        dec.set_output_hash(true);
+        assert_eq!(dec.output_hash(), FNV_OFFSET);
+        decode(&mut dec, &fixture::audio_packets(&[true, true], TONE));
+        dec.flush().unwrap();
+        assert_eq!(dec.output_hash(), FNV_OFFSET);
+    }
+
+    #[test]
+    fn ten_channels_pass_through_in_stream_order() {
+        let blocks = [true, false, true];
+        let stereo = pcm_i16(&decode(
+            &mut fixture::decoder(2),
+            &fixture::audio_packets(&blocks, TONE),
+        ));
+        let amps = [255, 200, 0, 0, 0, 0, 0, 0, 200, 255];
+        let frames = decode(
+            &mut fixture::decoder(10),
+            &fixture::audio_packets(&blocks, &amps),
+        );
+        let map = &audio_info(&frames[0]).map;
+        assert_eq!(map.len(), 10);
+        assert!((0..10).all(|c| map.get_channel(c) == ChannelType::C));
+        let pcm = pcm_i16(&frames);
+        for (c, &amp) in amps.iter().enumerate() {
+            let expected = match amp {
+                255 => channel(&stereo, 2, 0),
+                200 => channel(&stereo, 2, 1),
+                _ => vec![0; stereo.len() / 2],
+            };
+            assert_eq!(channel(&pcm, 10, c), expected, "channel {}", c);
+        }
+    }
+
+    #[test]
+    fn latency_bounds_the_held_back_samples() {
+        assert_eq!(Dec::new().latency_samples(), 0);
+        let packets = fixture::audio_packets(&[true; 12], TONE);
+        for &size in &[None, Some(480)] {
+            let mut dec = fixture::decoder(2);
+            dec.set_frame_size(size);
+            let latency = dec.latency_samples();
+            assert_eq!(latency, 1024 + size.map_or(0, |size| size - 1));
+            let mut emitted = 0;
+            let mut held = Vec::new();
+            for (i, data) in packets.iter().enumerate() {
+                dec.send_packet(&fixture::packet(data)).unwrap();
+                emitted += total_len(&receive_all(&mut dec));
+                // Each long block adds 1024 samples to the stream
+                held.push((i + 1) * 1024 - emitted);
+            }
+            assert!(held.iter().all(|&h| h <= latency));
+            if size.is_none() {
+                assert!(held.iter().all(|&h| h == latency));
+            }
+        }
+    }
+
+    #[test]
+    fn installed_setup_is_reused_by_compatible_streams() {
+        let first = fixture::decoder(2);
+        let setup = first.parsed_setup().unwrap();
+        let packets = fixture::audio_packets(&[true, false, true], TONE);
+        let expected = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        // Another track of the same encoder, with other comments
+        let mut headers = fixture::headers(2, 44100);
+        headers[1] = fixture::comment(b"fixture", &[b"TITLE=second"]);
+        let mut dec = Dec::new();
+        dec.install_setup(setup.clone());
+        dec.configure_from(&fixture::lace_headers(&headers))
+            .unwrap();
+        assert!(Arc::ptr_eq(&dec.parsed_setup().unwrap(), &setup));
+        assert_eq!(pcm_i16(&decode(&mut dec, &packets)), expected);
+        // The setup of a stream with another channel count is parsed anew
+        let mut dec = Dec::new();
+        dec.install_setup(setup.clone());
+        dec.configure_from(&fixture::extradata(1, 48000)).unwrap();
+        assert!(!Arc::ptr_eq(&dec.parsed_setup().unwrap(), &setup));
+
+        // Hashing the same is not enough to be reused
+        let mut headers = fixture::headers(2, 48000);
+        headers[2] = fixture::setup_coupled(2, &[(0, 1)]);
+        let blocksizes = fixture::BLOCKSIZES;
+        let forged = Arc::new(ParsedSetup {
+            hash: setup_hash(&setup_key(&headers[2], 2, blocksizes)),
+            key: setup_key(&fixture::setup(), 2, blocksizes),
+            header: read_header_setup(&fixture::setup(), 2, blocksizes).unwrap(),
+            info: None,
+        });
+        let mut dec = Dec::new();
+        dec.install_setup(forged.clone());
+        dec.configure_from(&fixture::lace_headers(&headers))
+            .unwrap();
+        assert!(!Arc::ptr_eq(&dec.parsed_setup().unwrap(), &forged));
+    }
+
+    #[test]
+    fn unrepresentable_frames_fail_gracefully() {
+        let mut dec = fixture::decoder(2);
+        let mut info = dec.info.clone();
+        info.samples = usize::MAX / 4;
+        let err = dec
+            .get_frame(info.clone(), TimeInfo::default())
+            .unwrap_err();
+        assert_eq!(error_category(&err), "allocation");
+        info.samples = isize::MAX as usize / 2;
+        assert!(dec.get_frame(info, TimeInfo::default()).is_err());
+        // A provider failing is reported the same way, and leaves the
+        // packet to be decoded once it succeeds again
+        let packets = fixture::audio_packets(&[true, false, true, true], TONE);
+        let expected = decode(&mut fixture::decoder(2), &packets);
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_provider(Some(Box::new(|_info: &AudioInfo| None)));
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        let err = dec.send_packet(&fixture::packet(&packets[1])).unwrap_err();
+        assert_eq!(error_category(&err), "allocation");
+        assert_eq!(dec.samples_decoded(), 0);
+        dec.set_frame_provider(None);
+        let frames = decode(&mut dec, &packets[1..]);
+        assert_eq!(pcm_i16(&frames), pcm_i16(&expected));
+    }
+
+    #[test]
+    fn builder_applies_every_option() {
+        let budget = ErrorBudget {
+            max_consecutive: 2,
+            max_total: 5,
+        };
+        let dec = DecBuilder::new()
+            .streaming(true)
+            .output_format(OutputFormat::S24)
+            .planar(true)
+            .channel_selection(&[1])
+            .force_stereo(true)
+            .max_pending(Some(3))
+            .peak_metering(true)
+            .frame_pool(2)
+            .frame_flags(true)
+            .output_hash(true)
+            .frame_size(Some(480))
+            .in_band_headers(true)
+            .header_resync(16)
+            .error_budget(Some(budget))
+            .gapless(true)
+            .intra_only(true)
+            .laced_packets(true)
+            .retain_headers(true)
+            .extradata(&fixture::extradata(2, 44100))
+            .build()
+            .unwrap();
+        assert!(!dec.tail_drain);
+        assert_eq!(dec.output_format, OutputFormat::S24);
+        assert!(dec.info.format.planar);
+        assert_eq!(dec.channel_selection, Some(vec![1]));
+        assert!(dec.force_stereo);
+        assert_eq!(dec.info.map.len(), 2);
+        assert_eq!(dec.max_pending, Some(3));
+        assert!(dec.peaks.is_some());
+        assert_eq!(dec.pool_size, 2);
+        assert!(dec.frame_flags);
+        assert_eq!(dec.output_hash(), FNV_OFFSET);
+        assert_eq!(dec.frame_size, Some(480));
+        assert!(dec.in_band_headers);
+        assert_eq!(dec.resync_window, 16);
+        assert_eq!(dec.error_budget, Some(budget));
+        assert!(dec.gapless && dec.intra_only && dec.laced);
+        assert!(dec.to_ffmpeg_extradata().is_some());
+        assert_eq!(dec.info.sample_rate, 44100);
+
+        let mut dec = DecBuilder::new()
+            .output_channel_order(ChannelOrder::Smpte)
+            .frame_provider(Box::new(|_info: &AudioInfo| None))
+            .extradata(&fixture::extradata(6, 48000))
+            .build()
+            .unwrap();
+        assert_eq!(dec.channel_order, ChannelOrder::Smpte);
+        assert_eq!(dec.output_channels, Some(vec![0, 2, 1, 5, 3, 4]));
+        let packets = fixture::audio_packets(&[true, true], SURROUND);
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        let err = dec.send_packet(&fixture::packet(&packets[1])).unwrap_err();
+        assert_eq!(error_category(&err), "allocation");
+    }
+
+    #[test]
+    fn builder_rejects_conflicting_options() {
+        let provider = || -> FrameProvider { Box::new(|_info: &AudioInfo| None) };
+        let conflicts = [
+            DecBuilder::new()
+                .channel_selection(&[0, 1])
+                .output_channel_order(ChannelOrder::WaveExtensible),
+            DecBuilder::new()
+                .channel_selection(&[0, 1])
+                .force_stereo(true),
+            DecBuilder::new().frame_pool(4).frame_provider(provider()),
+        ];
+        for builder in conflicts.iter() {
+            assert!(matches!(
+                builder.clone().build(),
+                Err(Error::ConfigurationInvalid)
+            ));
+        }
+        assert!(DecBuilder::new()
+            .channel_selection(&[0])
+            .force_stereo(true)
+            .frame_provider(provider())
+            .build()
+            .is_ok());
+    }
+
+    /// Reads the fixture headers of a stream of `channels` channels with
+    /// lewton.
+    fn lewton_headers(channels: u8) -> HeaderSet {
+        let h = fixture::headers(channels, 48000);
+        let ident = read_header_ident(&h[0]).unwrap();
+        let comment = lewton::header::read_header_comment(&h[1]).unwrap();
+        let blocksizes = (ident.blocksize_0, ident.blocksize_1);
+        let setup = read_header_setup(&h[2], channels, blocksizes).unwrap();
+        (ident, comment, setup)
+    }
+
+    #[test]
+    fn decode_one_matches_the_decoder() {
+        let headers = lewton_headers(2);
+        let packets = fixture::audio_packets(&[true, false, false, true], TONE);
+        let mut pwr = PreviousWindowRight::new();
+        let mut pcm = Vec::new();
+        for data in &packets {
+            let samples = decode_one(&headers, &fixture::packet(data), &mut pwr).unwrap();
+            for i in 0..samples[0].len() {
+                pcm.extend(samples.iter().map(|chan| chan[i]));
+            }
+        }
+        let mut dec = fixture::configure(Dec::new_streaming(), 2);
+        assert_eq!(pcm, pcm_i16(&decode(&mut dec, &packets)));
+        assert!(decode_one(&headers, &fixture::packet(&[1, 2]), &mut pwr).is_err());
+    }
+
+    #[test]
+    fn coupling_past_the_channels_is_a_mismatch() {
+        let mut headers = fixture::headers(3, 48000);
+        headers[2] = fixture::setup_coupled(3, &[(0, 1)]);
+        let mut dec = Dec::new();
+        dec.configure_from(&fixture::lace_headers(&headers))
+            .unwrap();
+
+        headers[2] = fixture::setup_coupled(3, &[(0, 3)]);
+        let mut dec = Dec::new();
+        let ret = dec.configure_from(&fixture::lace_headers(&headers));
+        assert!(matches!(ret, Err(Error::InvalidData)));
+        assert_eq!(
+            dec.config_error(),
+            Some("channel count mismatch between ident and setup")
+        );
+    }
+
+    #[test]
+    fn fixed_frame_size_repacketizes() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true, false], TONE);
+        let plain = decode(&mut fixture::decoder(2), &packets);
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_size(Some(1000));
+        let frames = decode(&mut dec, &packets);
+        let (last, rest) = frames.split_last().unwrap();
+        assert!(!rest.is_empty());
+        for f in rest {
+            assert_eq!(samples_per_channel(audio_info(f)), 1000);
+        }
+        assert!(samples_per_channel(audio_info(last)) <= 1000);
+        assert_eq!(total_len(&frames), total_len(&plain));
+        assert_eq!(pcm_i16(&frames), pcm_i16(&plain));
+    }
+
+    #[test]
+    fn peek_frame_returns_the_next_frame() {
+        let mut dec = fixture::decoder(2);
+        assert!(dec.peek_frame().is_none());
+        for data in &fixture::audio_packets(&[true, false, true], TONE) {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        let peeked = dec.peek_frame().unwrap() as *const Frame;
+        let f = dec.receive_frame().unwrap();
+        assert_eq!(peeked, &*f as *const Frame);
+        assert!(dec.peek_frame().map(|p| p as *const Frame) != Some(peeked));
+    }
+
+    #[test]
+    fn frames_carry_their_duration() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true], TONE);
+        for &(timebase, scale) in &[(None, 1), (Some(Rational64::new(1, 1000)), 48)] {
+            let mut dec = fixture::decoder(2);
+            for data in &packets {
+                let mut pkt = fixture::packet(data);
+                pkt.t.timebase = timebase;
+                dec.send_packet(&pkt).unwrap();
+            }
+            let frames = receive_all(&mut dec);
+            assert!(!frames.is_empty());
+            for f in &frames {
+                let len = samples_per_channel(audio_info(f)) as u64;
+                assert_eq!(f.t.duration, Some(len / scale));
+            }
+        }
+    }
+
+    #[test]
+    fn every_supported_format_decodes() {
+        let formats = Des::supported_output_formats();
+        assert!(formats.contains(&OutputFormat::S16));
+        assert!(formats.contains(&OutputFormat::F32));
+        let packets = fixture::audio_packets(&[true, false, true], TONE);
+        for &format in formats {
+            let mut dec = fixture::decoder(2);
+            dec.set_output_format(format);
+            let frames = decode(&mut dec, &packets);
+            assert!(!frames.is_empty());
+            for f in &frames {
+                assert_eq!(*audio_info(f).format, format.soniton(false));
+            }
+        }
+        // The float samples are the ones lewton decodes
+        let mut dec = fixture::decoder(2);
+        dec.set_output_format(OutputFormat::F32);
+        let pcm: Vec<f32> = decode(&mut dec, &packets)
+            .iter()
+            .flat_map(|f| frame_to_le_bytes(f))
+            .collect::<Vec<u8>>()
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let mut dec = fixture::decoder(2);
+        dec.set_output_format(OutputFormat::F64);
+        let expected = pcm_f64(&decode(&mut dec, &packets));
+        assert!(!expected.is_empty());
+        assert_eq!(pcm.into_iter().map(f64::from).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn provider_supplies_every_frame() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let packets = fixture::audio_packets(&[true, false, false, true, true], TONE);
+        let plain = decode(&mut fixture::decoder(2), &packets);
+        for &planar in &[false, true] {
+            let mut dec = fixture::decoder(2);
+            dec.set_planar(planar);
+            let count = Arc::new(AtomicUsize::new(0));
+            let calls = count.clone();
+            dec.set_frame_provider(Some(Box::new(move |info: &AudioInfo| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Some(Frame::new_default_frame(info.clone(), None).buf)
+            })));
+            let frames = decode(&mut dec, &packets);
+            assert_eq!(count.load(Ordering::SeqCst), frames.len());
+            assert_eq!(total_len(&frames), total_len(&plain));
+        }
+
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_provider(Some(Box::new(|_info: &AudioInfo| None)));
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        assert!(dec.send_packet(&fixture::packet(&packets[1])).is_err());
+    }
+
+    #[test]
+    fn in_band_headers_defer_audio() {
+        let headers = fixture::headers(2, 48000);
+        let packets = fixture::audio_packets(&[true, false, false, true], TONE);
+        let mut dec = Dec::new();
+        dec.set_in_band_headers(true);
+        let early = dec.send_packet(&fixture::packet(&packets[0]));
+        assert!(matches!(early, Err(Error::MoreDataNeeded)));
+        let setup_first = dec.send_packet(&fixture::packet(&headers[2]));
+        assert!(matches!(setup_first, Err(Error::MoreDataNeeded)));
+        dec.send_packet(&fixture::packet(&headers[0])).unwrap();
+        dec.send_packet(&fixture::packet(&headers[1])).unwrap();
+        let before_setup = dec.send_packet(&fixture::packet(&packets[0]));
+        assert!(matches!(before_setup, Err(Error::MoreDataNeeded)));
+        dec.send_packet(&fixture::packet(&headers[2])).unwrap();
+        assert!(receive_all(&mut dec).is_empty());
+
+        let frames = decode(&mut dec, &packets);
+        let expected = decode(&mut fixture::decoder(2), &packets);
+        assert_eq!(pcm_i16(&frames), pcm_i16(&expected));
+    }
+
+    #[test]
+    fn decoder_errors_are_categorized() {
+        let packets = fixture::audio_packets(&[true, false, true], TONE);
+        let mut errors = Vec::new();
+
+        let mut dec = Dec::new();
+        errors.push((
+            dec.send_packet(&fixture::packet(&packets[0])),
+            "configuration",
+        ));
+        let mut headers = fixture::headers(2, 48000);
+        headers[0][0] = 7;
+        errors.push((dec.configure_from(&fixture::lace_headers(&headers)), "data"));
+        dec.set_in_band_headers(true);
+        errors.push((dec.send_packet(&fixture::packet(&packets[0])), "underflow"));
+
+        let mut dec = fixture::decoder(2);
+        errors.push((dec.send_packet(&fixture::packet(&[1, 2])), "data"));
+        dec.set_error_budget(Some(ErrorBudget {
+            max_consecutive: 0,
+            max_total: 0,
+        }));
+        errors.push((dec.send_packet(&fixture::packet(&[1, 2])), "data"));
+
+        let mut dec = fixture::decoder(2);
+        dec.set_max_pending(Some(1));
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        dec.send_packet(&fixture::packet(&packets[1])).unwrap();
+        errors.push((
+            dec.send_packet(&fixture::packet(&packets[2])),
+            "backpressure",
+        ));
+
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_provider(Some(Box::new(|_info: &AudioInfo| None)));
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        errors.push((dec.send_packet(&fixture::packet(&packets[1])), "allocation"));
+
+        for (ret, category) in errors {
+            assert_eq!(error_category(&ret.unwrap_err()), category);
+        }
+        let other = Error::Unsupported("other".to_owned());
+        assert_eq!(error_category(&other), "unsupported");
+    }
+
+    #[test]
+    fn cover_art_is_read_from_the_comments() {
+        let image = vec![0x89; 300];
+        let picture = format!(
+            "METADATA_BLOCK_PICTURE={}",
+            fixture::base64(&fixture::picture_block("image/jpeg", &image))
+        );
+        let comments: &[&[u8]] = &[b"METADATA_BLOCK_PICTURE=!", picture.as_bytes()];
+        let mut headers = fixture::headers(2, 48000);
+        headers[1] = fixture::comment(b"fixture", comments);
+        let mut dec = Dec::new();
+        assert_eq!(dec.cover_art(), None);
+        dec.configure_from(&fixture::lace_headers(&headers))
+            .unwrap();
+        let art = dec.cover_art().unwrap();
+        assert_eq!(art.mime, "image/jpeg");
+        assert_eq!(art.data.len(), image.len());
+        assert_eq!(fixture::decoder(2).cover_art(), None);
+    }
+
+    #[test]
+    fn block_size_transitions_size_the_frames() {
+        let blocks = [false, true, true, false, false, true, false, true, true];
+        let packets = fixture::audio_packets(&blocks, TONE);
+        let expected: Vec<usize> = fixture::decoded_lengths(&packets)
+            .into_iter()
+            .filter(|&len| len > 0)
+            .collect();
+        for &planar in &[false, true] {
+            let mut dec = Dec::new_streaming();
+            dec.set_planar(planar);
+            let mut dec = fixture::configure(dec, 2);
+            let frames = decode(&mut dec, &packets);
+            let lengths: Vec<usize> = frames
+                .iter()
+                .map(|f| samples_per_channel(audio_info(f)))
+                .collect();
+            assert_eq!(lengths, expected);
+            for (f, &len) in frames.iter().zip(&expected) {
+                assert_eq!(frame_to_le_bytes(f).len(), len * 2 * 2);
+            }
+        }
+    }
+
+    #[test]
+    fn f64_output_matches_the_f32_decode() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true], TONE);
+        let headers = lewton_headers(2);
+        let mut pwr = PreviousWindowRight::new();
+        let mut expected = vec![Vec::new(), Vec::new()];
+        for data in &packets {
+            let samples: Vec<Vec<f32>> =
+                read_audio_packet_generic(&headers.0, &headers.2, data, &mut pwr).unwrap();
+            for (chan, samples) in expected.iter_mut().zip(samples) {
+                chan.extend(samples.into_iter().map(f64::from));
+            }
+        }
+
+        let mut dec = Dec::new_streaming();
+        dec.set_output_format(OutputFormat::F64);
+        let mut dec = fixture::configure(dec, 2);
+        let frames = decode(&mut dec, &packets);
+        let mut planes = vec![Vec::new(), Vec::new()];
+        for f in &frames {
+            let info = audio_info(f);
+            assert!(info.format.planar);
+            for (i, plane) in planes.iter_mut().enumerate() {
+                let data = f.buf.as_slice_inner(i).unwrap();
+                plane.extend(data[..info.samples * 8].chunks(8).map(|b| {
+                    let mut v = [0; 8];
+                    v.copy_from_slice(b);
+                    f64::from_le_bytes(v)
+                }));
+            }
+        }
+        assert!(!expected[0].is_empty());
+        assert_eq!(planes, expected);
+    }
+
+    #[test]
+    fn profiling_hook_sees_every_packet_once() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true], TONE);
+        let stats = Arc::new(Mutex::new(Vec::new()));
+        let seen = stats.clone();
+        let mut dec = fixture::decoder(2);
+        dec.set_profiling_hook(Some(Box::new(move |s: PacketStats| {
+            seen.lock().unwrap().push((s.bytes, s.samples));
+        })));
+        dec.set_error_budget(Some(ErrorBudget {
+            max_consecutive: 1,
+            max_total: 1,
+        }));
+        for data in &packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        dec.send_packet(&fixture::packet(&[1, 2])).unwrap();
+        let expected: Vec<(usize, usize)> = packets
+            .iter()
+            .map(Vec::len)
+            .zip(fixture::decoded_lengths(&packets))
+            .collect();
+        assert_eq!(*stats.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn header_resync_skips_leading_junk() {
+        let headers = fixture::headers(2, 48000);
+        let packets = fixture::audio_packets(&[true, false, true], TONE);
+        let mut ident = vec![0xde, 0xad, 0x01];
+        ident.extend_from_slice(&headers[0]);
+        for &(window, ok) in &[(0, false), (2, false), (3, true), (16, true)] {
+            let mut dec = Dec::new();
+            dec.set_in_band_headers(true);
+            dec.set_header_resync(window);
+            let ret = dec.send_packet(&fixture::packet(&ident));
+            assert_eq!(ret.is_ok(), ok, "window {}", window);
+            if !ok {
+                continue;
+            }
+            for header in &headers[1..] {
+                dec.send_packet(&fixture::packet(header)).unwrap();
+            }
+            assert_eq!(dec.info.sample_rate, 48000);
+            assert_eq!(
+                pcm_i16(&decode(&mut dec, &packets)),
+                pcm_i16(&decode(&mut fixture::decoder(2), &packets))
+            );
+        }
+    }
+
+    #[test]
+    fn output_channel_order_places_the_channels() {
+        use self::ChannelType::*;
+        let amps: &[u8] = &[255, 250, 245, 240, 235, 230, 225, 220];
+        let cases: &[(usize, ChannelOrder, &[ChannelType], &[usize])] = &[
+            (
+                6,
+                ChannelOrder::Vorbis,
+                &[L, C, R, Ls, Rs, LFE],
+                &[0, 1, 2, 3, 4, 5],
+            ),
+            (
+                6,
+                ChannelOrder::WaveExtensible,
+                &[L, R, C, LFE, Ls, Rs],
+                &[0, 2, 1, 5, 3, 4],
+            ),
+            (
+                6,
+                ChannelOrder::Smpte,
+                &[L, R, C, LFE, Ls, Rs],
+                &[0, 2, 1, 5, 3, 4],
+            ),
+            (
+                8,
+                ChannelOrder::WaveExtensible,
+                &[L, R, C, LFE, Ls, Rs, Lss, Rss],
+                &[0, 2, 1, 7, 5, 6, 3, 4],
+            ),
+            (
+                8,
+                ChannelOrder::Smpte,
+                &[L, R, C, LFE, Lss, Rss, Ls, Rs],
+                &[0, 2, 1, 7, 3, 4, 5, 6],
+            ),
+        ];
+        for &(channels, order, ids, sources) in cases {
+            let packets = fixture::audio_packets(&[true, false, true], &amps[..channels]);
+            let full = pcm_i16(&decode(&mut fixture::decoder(channels as u8), &packets));
+            for c in 1..channels {
+                assert!(channel(&full, channels, c - 1) != channel(&full, channels, c));
+            }
+            let mut dec = fixture::decoder(channels as u8);
+            dec.set_output_channel_order(order);
+            let frames = decode(&mut dec, &packets);
+            let map = &audio_info(&frames[0]).map;
+            let placed: Vec<ChannelType> = (0..map.len()).map(|c| map.get_channel(c)).collect();
+            assert_eq!(placed, ids, "{:?}", order);
+            let pcm = pcm_i16(&frames);
+            for (c, &source) in sources.iter().enumerate() {
+                assert_eq!(channel(&pcm, channels, c), channel(&full, channels, source));
+            }
+        }
+    }
+
+    #[test]
+    fn last_block_kind_follows_the_packets() {
+        let blocks = [false, true, true, false, false, true, false];
+        let packets = fixture::audio_packets(&blocks, TONE);
+        let mut dec = fixture::decoder(2);
+        assert_eq!(dec.last_block_kind(), None);
+        for (data, &long) in packets.iter().zip(&blocks) {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+            let kind = if long {
+                BlockKind::Long
+            } else {
+                BlockKind::Short
+            };
+            assert_eq!(dec.last_block_kind(), Some(kind));
+        }
+        dec.flush().unwrap();
+        assert_eq!(dec.last_block_kind(), None);
+    }
+
+    #[test]
+    fn decode_all_with_hands_over_every_frame() {
+        let blocks: Vec<bool> = (0..200).map(|i| i % 3 != 1).collect();
+        let packets = fixture::audio_packets(&blocks, TONE);
+        let expected = total_len(&decode(&mut fixture::decoder(2), &packets));
+        let mut dec = fixture::decoder(2);
+        let mut samples = 0;
+        let packets_iter = packets.iter().map(|data| fixture::packet(data));
+        dec.decode_all_with(packets_iter, |f| {
+            // Not kept by the decoder either
+            assert_eq!(Arc::strong_count(&f), 1);
+            samples += samples_per_channel(audio_info(&f));
+        })
+        .unwrap();
+        assert_eq!(samples, expected);
+        assert_eq!(dec.pending_frames(), 0);
+    }
+
+    #[test]
+    fn coalescing_merges_the_queued_frames() {
+        let packets = fixture::audio_packets(&[false; 6], TONE);
+        for &planar in &[false, true] {
+            let send_all = |dec: &mut Dec| {
+                dec.set_planar(planar);
+                for (i, data) in packets.iter().enumerate() {
+                    let mut pkt = fixture::packet(data);
+                    pkt.t.pts = Some(i as i64 * 1000);
+                    dec.send_packet(&pkt).unwrap();
+                }
+            };
+            let mut dec = fixture::decoder(2);
+            send_all(&mut dec);
+            let single = receive_all(&mut dec);
+            assert_eq!(single.len(), 5);
+
+            let mut dec = fixture::decoder(2);
+            send_all(&mut dec);
+            let merged = dec.receive_coalesced(3 * 128 - 1).unwrap();
+            let info = audio_info(&merged);
+            assert_eq!(samples_per_channel(info), 2 * 128);
+            assert_eq!(info.format.planar, planar);
+            assert_eq!(merged.t.pts, single[0].t.pts);
+            assert_eq!(merged.t.duration, Some(2 * 128));
+            assert_eq!(pcm_i16(&[merged]), pcm_i16(&single[..2]));
+            let rest = dec.receive_coalesced(usize::MAX).unwrap();
+            assert_eq!(rest.t.pts, single[2].t.pts);
+            assert_eq!(pcm_i16(&[rest]), pcm_i16(&single[2..]));
+            assert!(matches!(
+                dec.receive_coalesced(128),
+                Err(Error::MoreDataNeeded)
+            ));
+        }
+    }
+
+    #[test]
+    fn force_stereo_duplicates_mono() {
+        use self::ChannelType::*;
+        let packets = fixture::audio_packets(&[true, false, true, true], &[255]);
+        let mono = pcm_i16(&decode(&mut fixture::decoder(1), &packets));
+        let mut dec = fixture::decoder(1);
+        dec.set_force_stereo(true);
+        let frames = decode(&mut dec, &packets);
+        let map = &audio_info(&frames[0]).map;
+        assert_eq!(
+            (map.len(), map.get_channel(0), map.get_channel(1)),
+            (2, L, R)
+        );
+        let pcm = pcm_i16(&frames);
+        assert_eq!(channel(&pcm, 2, 0), mono);
+        assert_eq!(channel(&pcm, 2, 1), mono);
+
+        // Streams with more channels are left alone
+        let mut dec = fixture::decoder(2);
+        dec.set_force_stereo(true);
+        let packets = fixture::audio_packets(&[true, true], TONE);
+        let stereo = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        assert_eq!(pcm_i16(&decode(&mut dec, &packets)), stereo);
+    }
+
+    #[test]
+    fn empty_last_packet_ends_the_stream_cleanly() {
+        let packets = fixture::audio_packets(&[true, false, true, true], TONE);
+        let expected = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        let mut dec = fixture::decoder(2);
+        let mut truncated = packets.clone();
+        truncated.push(Vec::new());
+        assert_eq!(pcm_i16(&decode(&mut dec, &truncated)), expected);
+        assert!(dec.was_truncated());
+
+        // Followed by more audio the empty packet is skipped
+        let mut dec = fixture::decoder(2);
+        dec.set_error_budget(Some(ErrorBudget {
+            max_consecutive: 0,
+            max_total: 0,
+        }));
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        dec.send_packet(&fixture::packet(&[])).unwrap();
+        for data in &packets[1..] {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        dec.send_eof().unwrap();
+        assert!(!dec.was_truncated());
+        assert_eq!(dec.recovered_errors(), 0);
+        assert_eq!(pcm_i16(&receive_all(&mut dec)), expected);
+    }
+
+    #[test]
+    fn ident_header_exposes_the_fields() {
+        assert!(Dec::new().ident_header().is_none());
+        let dec = fixture::decoder(6);
+        let ident = dec.ident_header().unwrap();
+        assert_eq!(ident.audio_channels, 6);
+        assert_eq!(ident.audio_sample_rate, 48000);
+        assert_eq!(ident.bitrate_nominal, 128_000);
+        assert_eq!((ident.bitrate_maximum, ident.bitrate_minimum), (0, 0));
+        assert_eq!((ident.blocksize_0, ident.blocksize_1), fixture::BLOCKSIZES);
+    }
+
+    #[test]
+    fn exhausted_error_budget_is_terminal() {
+        let packets = fixture::audio_packets(&[true; 8], TONE);
+        let budget = ErrorBudget {
+            max_consecutive: 2,
+            max_total: 3,
+        };
+        let bad = fixture::packet(&[1, 2]);
+        let mut dec = fixture::decoder(2);
+        dec.set_error_budget(Some(budget));
+        dec.send_packet(&bad).unwrap();
+        dec.send_packet(&bad).unwrap();
+        let third = dec.send_packet(&bad).unwrap_err();
+        assert!(is_error_budget_exhausted(&third));
+
+        let mut dec = fixture::decoder(2);
+        dec.set_error_budget(Some(budget));
+        let mut good = packets.iter().map(|data| fixture::packet(data));
+        for pkt in &[&bad, &bad] {
+            dec.send_packet(pkt).unwrap();
+        }
+        dec.send_packet(&good.next().unwrap()).unwrap();
+        dec.send_packet(&bad).unwrap();
+        assert_eq!(dec.recovered_errors(), 3);
+        dec.send_packet(&good.next().unwrap()).unwrap();
+        assert!(is_error_budget_exhausted(
+            &dec.send_packet(&bad).unwrap_err()
+        ));
+        let after = dec.send_packet(&good.next().unwrap()).unwrap_err();
+        assert!(is_error_budget_exhausted(&after));
+        assert!(!is_error_budget_exhausted(&Error::InvalidData));
+
+        dec.flush().unwrap();
+        assert_eq!(dec.recovered_errors(), 0);
+        dec.send_packet(&good.next().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn repeated_comment_keys_keep_every_value() {
+        let comments: &[&[u8]] = &[
+            b"ARTIST=first",
+            b"TITLE=song",
+            b"artist=second",
+            b"Artist=third",
+        ];
+        let mut headers = fixture::headers(2, 48000);
+        headers[1] = fixture::comment(b"fixture", comments);
+        let mut dec = Dec::new();
+        assert!(dec.comment_values("ARTIST").is_empty());
+        dec.configure_from(&fixture::lace_headers(&headers))
+            .unwrap();
+        assert_eq!(dec.comment_values("Artist"), ["first", "second", "third"]);
+        assert_eq!(dec.comment_values("title"), ["song"]);
+        assert!(dec.comment_values("ALBUM").is_empty());
+        assert_eq!(dec.comment_header().unwrap().comment_list.len(), 4);
+    }
+
+    #[test]
+    fn skipped_packets_resume_at_their_end() {
+        let blocks = [true, false, false, true, true, false, true, true, true];
+        let packets = fixture::audio_packets(&blocks, TONE);
+        // Where each packet ends, as decoded
+        let mut dec = fixture::decoder(2);
+        let mut granules = Vec::new();
+        let mut pos = 0;
+        for data in &packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+            pos += total_len(&receive_all(&mut dec)) as u64;
+            granules.push(pos);
+        }
+        let full = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        for n in 1..packets.len() {
+            let mut dec = fixture::decoder(2);
+            let skipped = packets[..n].iter().map(|data| fixture::packet(data));
+            dec.skip_packets(skipped, n).unwrap();
+            assert_eq!(dec.samples_decoded(), granules[n - 1]);
+            let pcm = pcm_i16(&decode(&mut dec, &packets[n..]));
+            assert_eq!(pcm, &full[granules[n - 1] as usize * 2..]);
+        }
+
+        // Laced in pairs, with samples buffered for a frame size
+        let laced: Vec<Vec<u8>> = packets.chunks(2).map(fixture::lace).collect();
+        let mut dec = Dec::new();
+        dec.set_laced_packets(true);
+        dec.set_frame_size(Some(100));
+        let mut dec = fixture::configure(dec, 2);
+        dec.send_packet(&fixture::packet(&laced[0])).unwrap();
+        let _ = receive_all(&mut dec);
+        let skipped = laced[1..].iter().map(|data| fixture::packet(data));
+        dec.skip_packets(skipped, 2).unwrap();
+        assert_eq!(dec.samples_decoded(), granules[5]);
+        let pcm = pcm_i16(&decode(&mut dec, &laced[3..]));
+        assert_eq!(pcm, &full[granules[5] as usize * 2..]);
+
+        // Nothing from before the skip is left over
+        let mut dec = fixture::decoder(2);
+        for data in &packets[..2] {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        dec.send_packet(&fixture::packet(&[])).unwrap();
+        assert!(dec.instantaneous_bitrate().is_some());
+        let skipped = packets[2..].iter().map(|data| fixture::packet(data));
+        dec.skip_packets(skipped, 2).unwrap();
+        assert_eq!(dec.instantaneous_bitrate(), None);
+        dec.send_eof().unwrap();
+        assert!(!dec.was_truncated());
+    }
+
+    #[test]
+    fn pending_counts_follow_the_queue() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true], TONE);
+        let lengths = fixture::decoded_lengths(&packets);
+        let mut dec = fixture::decoder(2);
+        assert_eq!((dec.pending_frames(), dec.pending_samples()), (0, 0));
+        for data in &packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+        }
+        let mut frames = 4;
+        let mut samples: usize = lengths.iter().sum();
+        for len in &lengths[1..] {
+            assert_eq!(
+                (dec.pending_frames(), dec.pending_samples()),
+                (frames, samples)
+            );
+            dec.receive_frame().unwrap();
+            frames -= 1;
+            samples -= len;
+        }
+        assert_eq!((dec.pending_frames(), dec.pending_samples()), (0, 0));
+    }
+
+    #[test]
+    fn empty_headers_are_rejected() {
+        let packed = |lens: &[u8], headers: &[&[u8]]| {
+            let mut data = vec![2];
+            data.extend_from_slice(lens);
+            for header in headers {
+                data.extend_from_slice(header);
+            }
+            data
+        };
+        let h = fixture::headers(2, 48000);
+        let (ident, comment) = (h[0].len() as u8, h[1].len() as u8);
+        let cases: &[(Vec<u8>, &str)] = &[
+            (
+                packed(&[0, comment], &[&h[1], &h[2]]),
+                "empty identification header",
+            ),
+            (packed(&[ident, 0], &[&h[0], &h[2]]), "empty comment header"),
+            (
+                packed(&[ident, comment], &[&h[0], &h[1]]),
+                "empty setup header",
+            ),
+        ];
+        for &(ref extradata, error) in cases {
+            let mut dec = Dec::new();
+            let ret = dec.configure_from(extradata);
+            assert!(matches!(ret, Err(Error::InvalidData)));
+            assert_eq!(dec.config_error(), Some(error));
+        }
+    }
+
+    /// Sends two chained streams with in-band headers, the audio packets
+    /// split after the fourth, and ends the stream.
+    fn decode_chained(dec: &mut Dec, packets: &[Vec<u8>], second: &[Vec<u8>; 3]) -> Vec<ArcFrame> {
+        dec.set_in_band_headers(true);
+        let (first, rest) = packets.split_at(4);
+        let mut frames = Vec::new();
+        for &(headers, audio) in &[(&fixture::headers(2, 48000), first), (second, rest)] {
+            for header in headers.iter() {
+                dec.send_packet(&fixture::packet(header)).unwrap();
+            }
+            for data in audio {
+                dec.send_packet(&fixture::packet(data)).unwrap();
+                frames.extend(receive_all(dec));
+            }
+        }
+        dec.send_eof().unwrap();
+        frames.extend(receive_all(dec));
+        frames
+    }
+
+    #[test]
+    fn gapless_joins_chained_streams() {
+        let packets = fixture::audio_packets(&[true, false, true, true, false, false, true], TONE);
+        let continuous = decode(&mut fixture::decoder(2), &packets);
+        let same = fixture::headers(2, 48000);
+
+        let mut dec = Dec::new();
+        dec.set_gapless(true);
+        let frames = decode_chained(&mut dec, &packets, &same);
+        assert_eq!(pcm_i16(&frames), pcm_i16(&continuous));
+
+        // Without it the overlap at the join is dropped
+        let frames = decode_chained(&mut Dec::new(), &packets, &same);
+        assert!(total_len(&frames) < total_len(&continuous));
+
+        // As it is for streams that can not be overlapped
+        let mut dec = Dec::new();
+        dec.set_gapless(true);
+        let frames = decode_chained(&mut dec, &packets, &fixture::headers(2, 44100));
+        assert!(total_len(&frames) < total_len(&continuous));
+        assert!(dec.took_new_config());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn malformed_frames_fail_validation() {
+        let packets = fixture::audio_packets(&[true, true], TONE);
+        let f = decode(&mut fixture::decoder(2), &packets).remove(0);
+        let info = audio_info(&f).clone();
+        validate_frame(&f, &info).unwrap();
+
+        let mut longer = info.clone();
+        longer.samples += 2;
+        assert!(matches!(
+            validate_frame(&f, &longer),
+            Err(Error::InvalidData)
+        ));
+        let mut other_rate = info.clone();
+        other_rate.sample_rate = 44100;
+        assert!(validate_frame(&f, &other_rate).is_err());
+        let mut other_format = info.clone();
+        other_format.format = Arc::new(S32);
+        assert!(validate_frame(&f, &other_format).is_err());
+
+        // Frames without a timestamp are fine
+        let untimed = Frame::new_default_frame(info.clone(), None);
+        validate_frame(&untimed, &info).unwrap();
+
+        // Claiming more or fewer samples than the buffer holds
+        for &samples in &[info.samples / 2, info.samples * 2] {
+            let mut other = info.clone();
+            other.samples = samples;
+            let mut frame = Frame::new_default_frame(info.clone(), None);
+            frame.buf = Frame::new_default_frame(other, None).buf;
+            assert!(validate_frame(&frame, &info).is_err());
+        }
+    }
+
+    #[test]
+    fn intra_only_packets_decode_in_parallel() {
+        use std::thread;
+
+        let blocks: Vec<bool> = (0..12).map(|i| i % 4 != 2).collect();
+        let packets = fixture::audio_packets(&blocks, TONE);
+        let intra_decoder = |setup: Option<Arc<ParsedSetup>>| {
+            let mut dec = Dec::new_streaming();
+            dec.set_intra_only(true);
+            if let Some(setup) = setup {
+                dec.install_setup(setup);
+            }
+            fixture::configure(dec, 2)
+        };
+        let mut dec = intra_decoder(None);
+        let sequential: Vec<Vec<i16>> = packets
+            .iter()
+            .map(|data| pcm_i16(&decode(&mut dec, std::slice::from_ref(data))))
+            .collect();
+
+        let setup = dec.parsed_setup().unwrap();
+        let threads: Vec<_> = packets
+            .iter()
+            .rev()
+            .cloned()
+            .map(|data| {
+                let mut dec = intra_decoder(Some(setup.clone()));
+                thread::spawn(move || pcm_i16(&decode(&mut dec, &[data])))
+            })
+            .collect();
+        let mut parallel: Vec<Vec<i16>> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        parallel.reverse();
+        assert_eq!(parallel, sequential);
+
+        // Every packet yields its right window faded out, as the tail
+        for (pcm, data) in sequential.iter().zip(&packets) {
+            assert_eq!(pcm.len(), fixture::tail_len(data) * 2);
+            assert!(pcm.iter().any(|&v| v != 0));
+        }
+    }
+
+    #[test]
+    fn device_period_frames() {
+        let blocks: Vec<bool> = (0..20).map(|i| i % 5 < 3).collect();
+        let packets = fixture::audio_packets(&blocks, TONE);
+        let plain = decode(&mut fixture::decoder(2), &packets);
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_size(Some(480));
+        let mut frames = Vec::new();
+        for data in &packets {
+            dec.send_packet(&fixture::packet(data)).unwrap();
+            frames.extend(receive_all(&mut dec));
+        }
+        // Nothing short before the end of the stream
+        for f in &frames {
+            assert_eq!(samples_per_channel(audio_info(f)), 480);
+        }
+        dec.send_eof().unwrap();
+        let last = receive_all(&mut dec);
+        let total = total_len(&plain);
+        assert_eq!(last.len(), total.div_ceil(480) - frames.len());
+        frames.extend(last);
+        assert!(samples_per_channel(audio_info(frames.last().unwrap())) <= 480);
+        assert_eq!(total_len(&frames), total);
+        assert_eq!(pcm_i16(&frames), pcm_i16(&plain));
+    }
+
+    #[test]
+    fn ffmpeg_extradata_round_trips() {
+        let mut headers = fixture::headers(2, 44100);
+        // Long enough for the comment size to take several lacing bytes
+        let mut comment = b"TITLE=".to_vec();
+        comment.resize(600, b'x');
+        headers[1] = fixture::comment(b"fixture", &[&comment]);
+        let extradata = fixture::lace_headers(&headers);
+        let mut dec = Dec::new();
+        dec.configure_from(&extradata).unwrap();
+        assert_eq!(dec.to_ffmpeg_extradata(), None);
+
+        dec.set_retain_headers(true);
+        assert_eq!(dec.to_ffmpeg_extradata(), None);
+        dec.configure_from(&extradata).unwrap();
+        let ffmpeg = dec.to_ffmpeg_extradata().unwrap();
+        assert_eq!(ffmpeg, extradata);
+        let mut copy = Dec::new();
+        copy.configure_from(&ffmpeg).unwrap();
+        assert_eq!(copy.audio_info().sample_rate, 44100);
+        assert_eq!(copy.comment_values("TITLE"), dec.comment_values("TITLE"));
+        assert_eq!(copy.comment_values("TITLE")[0].len(), 594);
+
+        dec.set_retain_headers(false);
+        assert_eq!(dec.to_ffmpeg_extradata(), None);
+    }
+
+    #[test]
+    fn laced_packets_fit_the_queue_as_a_whole() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true, true], TONE);
+        let expected = decode(&mut fixture::decoder(2), &packets);
+        let mut dec = Dec::new();
+        dec.set_laced_packets(true);
+        dec.set_max_pending(Some(2));
+        let mut dec = fixture::configure(dec, 2);
+        let mut frames = Vec::new();
+        for pair in packets.chunks(2) {
+            let pkt = fixture::packet(&fixture::lace(pair));
+            loop {
+                let before = dec.pending_frames();
+                match dec.send_packet(&pkt) {
+                    Ok(()) => break,
+                    Err(ref e) if is_queue_full(e) => {
+                        // Nothing of it was decoded
+                        assert_eq!(dec.pending_frames(), before);
+                        frames.push(dec.receive_frame().unwrap());
+                    }
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            if pair[0] != packets[0] {
+                // Both packets were decoded to a frame each
+                assert_eq!(dec.pending_frames(), 2);
+            }
+        }
+        frames.extend(receive_all(&mut dec));
+        dec.send_eof().unwrap();
+        frames.extend(receive_all(&mut dec));
+        assert_eq!(frames.len(), expected.len());
+        assert_eq!(pcm_i16(&frames), pcm_i16(&expected));
+    }
+
+    #[test]
+    fn only_the_first_frame_after_a_reset_is_a_seek_point() {
+        let packets = fixture::audio_packets(&[true, false, true, true, false], TONE);
+        let granules = fixture::granules(&packets);
+        let seek_points = |frames: &[ArcFrame]| -> Vec<bool> {
+            frames
+                .iter()
+                .map(|f| frame_flags(f).unwrap().seek_point)
+                .collect()
+        };
+        let mut dec = fixture::decoder(2);
+        dec.set_frame_flags(true);
+        let frames = decode(&mut dec, &packets);
+        assert_eq!(seek_points(&frames), [true, false, false, false, false]);
+        dec.send_packet(&fixture::packet(&packets[0])).unwrap();
+        dec.flush().unwrap();
+        let frames = decode(&mut dec, &packets[1..]);
+        assert_eq!(seek_points(&frames), [true, false, false, false]);
+        dec.seek_reset(granules[2]);
+        let frames = decode(&mut dec, &packets[2..]);
+        assert_eq!(seek_points(&frames), [true, false, false]);
+    }
+
+    #[test]
+    fn final_granule_sets_the_exact_length() {
+        let packets = fixture::audio_packets(&[true, false, true, true, true], TONE);
+        let full = *fixture::granules(&packets).last().unwrap();
+        let tail = fixture::tail_len(packets.last().unwrap()) as u64;
+        assert!(tail > 10);
+        // Without granule positions the whole tail is drained
+        let reference = pcm_i16(&decode(&mut fixture::decoder(2), &packets));
+        assert_eq!(reference.len() as u64, 2 * (full + tail));
+        for &end in &[full - 100, full, full + 10, full + tail] {
+            let mut granules = fixture::granules(&packets);
+            *granules.last_mut().unwrap() = end;
+            let frames = decode_with_granules(&mut fixture::decoder(2), &packets, &granules);
+            assert_eq!(total_len(&frames) as u64, end, "end {}", end);
+            let pcm = pcm_i16(&frames);
+            assert_eq!(pcm[..], reference[..pcm.len()]);
+        }
+    }
+
+    #[test]
+    fn laced_frames_are_timed_from_the_sample_position() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true, true], TONE);
+        let granules = fixture::granules(&packets);
+        let mut dec = Dec::new();
+        dec.set_laced_packets(true);
+        let mut dec = fixture::configure(dec, 2);
+        let mut frames = Vec::new();
+        for (i, pair) in packets.chunks(2).enumerate() {
+            let mut pkt = fixture::packet(&fixture::lace(pair));
+            let start = if i == 0 { 0 } else { granules[2 * i - 1] };
+            pkt.t = dec.sample_to_timestamp(start).unwrap();
+            dec.send_packet(&pkt).unwrap();
+            frames.extend(receive_all(&mut dec));
+        }
+        dec.send_eof().unwrap();
+        frames.extend(receive_all(&mut dec));
+        let mut pos = 0;
+        for f in &frames {
+            assert_eq!(f.t.pts, Some(pos));
+            pos += samples_per_channel(audio_info(f)) as i64;
+        }
+        let tail = fixture::tail_len(packets.last().unwrap());
+        assert_eq!(pos as u64, granules.last().unwrap() + tail as u64);
+    }
+}
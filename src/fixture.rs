@@ -0,0 +1,401 @@
+//! Vorbis and Ogg streams built bit by bit for the tests.
+//!
+//! The setup header is the smallest lewton accepts while still producing
+//! sound: two codebooks, a floor 1 without partitions, a residue covering
+//! the lowest frequency bin only, and a short and a long mode. An audio
+//! packet gives each channel a flat floor of the amplitude it is given, 0
+//! leaving the channel unused, and a unit residue, so every channel decodes
+//! to a windowed cosine whose level depends on its amplitude.
+
+use codec::decoder::Decoder;
+use data::packet::Packet;
+use decoder::Dec;
+use stream::page_crc;
+
+/// Exponents of the short and the long block size.
+pub const BLOCKSIZES: (u8, u8) = (8, 11);
+pub const SHORT: usize = 1 << BLOCKSIZES.0;
+pub const LONG: usize = 1 << BLOCKSIZES.1;
+
+/// Writer for the LSB-first bit packing of Vorbis.
+pub struct BitWriter {
+    data: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter {
+            data: Vec::new(),
+            bits: 0,
+        }
+    }
+
+    pub fn write(&mut self, v: u32, bits: u32) {
+        for i in 0..bits {
+            if self.bits.is_multiple_of(8) {
+                self.data.push(0);
+            }
+            let bit = ((v >> i) & 1) as u8;
+            *self.data.last_mut().unwrap() |= bit << (self.bits % 8);
+            self.bits += 1;
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+fn header_start(packet_type: u8) -> Vec<u8> {
+    let mut data = vec![packet_type];
+    data.extend_from_slice(b"vorbis");
+    data
+}
+
+pub fn ident(channels: u8, rate: u32) -> Vec<u8> {
+    let mut data = header_start(1);
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.push(channels);
+    data.extend_from_slice(&rate.to_le_bytes());
+    data.extend_from_slice(&0i32.to_le_bytes());
+    data.extend_from_slice(&128_000i32.to_le_bytes());
+    data.extend_from_slice(&0i32.to_le_bytes());
+    data.push(BLOCKSIZES.1 << 4 | BLOCKSIZES.0);
+    data.push(1);
+    data
+}
+
+pub fn comment(vendor: &[u8], comments: &[&[u8]]) -> Vec<u8> {
+    let mut data = header_start(3);
+    data.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    data.extend_from_slice(vendor);
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment);
+    }
+    data.push(1);
+    data
+}
+
+/// Writes a codebook of two entries of one bit each, mapped to 0.0 and
+/// 1.0 if `values`.
+fn write_codebook(w: &mut BitWriter, values: bool) {
+    w.write(0x56_43_42, 24);
+    w.write(1, 16);
+    w.write(2, 24);
+    // Neither ordered nor sparse, both lengths 1
+    w.write(0, 1);
+    w.write(0, 1);
+    w.write(0, 5);
+    w.write(0, 5);
+    if values {
+        w.write(1, 4);
+        // Minimum 0.0, delta 1.0
+        w.write(0, 32);
+        w.write(788 << 21 | 1, 32);
+        w.write(0, 4);
+        w.write(0, 1);
+        w.write(0, 1);
+        w.write(1, 1);
+    } else {
+        w.write(0, 4);
+    }
+}
+
+/// Builds a setup header with a short mode 0 and a long mode 1, for any
+/// channel count as there is no coupling.
+pub fn setup() -> Vec<u8> {
+    setup_coupled(1, &[])
+}
+
+/// Builds the setup header of `setup` with the coupling steps of a stream
+/// of `channels` channels.
+pub fn setup_coupled(channels: u8, coupling: &[(u32, u32)]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    for &b in &header_start(5) {
+        w.write(u32::from(b), 8);
+    }
+    w.write(1, 8);
+    write_codebook(&mut w, false);
+    write_codebook(&mut w, true);
+    // One time domain transform
+    w.write(0, 6);
+    w.write(0, 16);
+    // One floor 1 without partitions, multiplier 1, spanning 1024 bins
+    w.write(0, 6);
+    w.write(1, 16);
+    w.write(0, 5);
+    w.write(0, 2);
+    w.write(10, 4);
+    // One residue 1 over the first bin, classified by book 0, decoded by
+    // book 1 in the first pass
+    w.write(0, 6);
+    w.write(1, 16);
+    w.write(0, 24);
+    w.write(1, 24);
+    w.write(0, 24);
+    w.write(0, 6);
+    w.write(0, 8);
+    w.write(1, 3);
+    w.write(0, 1);
+    w.write(1, 8);
+    // One mapping
+    w.write(0, 6);
+    w.write(0, 16);
+    w.write(0, 1);
+    if coupling.is_empty() {
+        w.write(0, 1);
+    } else {
+        w.write(1, 1);
+        w.write(coupling.len() as u32 - 1, 8);
+        let bits = 32 - (u32::from(channels) - 1).leading_zeros();
+        for &(magnitude, angle) in coupling {
+            w.write(magnitude, bits);
+            w.write(angle, bits);
+        }
+    }
+    w.write(0, 2);
+    w.write(0, 8);
+    w.write(0, 8);
+    w.write(0, 8);
+    // A short and a long mode
+    w.write(1, 6);
+    for &long in &[false, true] {
+        w.write(long as u32, 1);
+        w.write(0, 16);
+        w.write(0, 16);
+        w.write(0, 8);
+    }
+    w.write(1, 1);
+    w.into_bytes()
+}
+
+pub fn headers(channels: u8, rate: u32) -> [Vec<u8>; 3] {
+    [ident(channels, rate), comment(b"fixture", &[]), setup()]
+}
+
+/// Lays out header packets as Matroska style extradata.
+pub fn lace_headers(headers: &[Vec<u8>; 3]) -> Vec<u8> {
+    lace(headers)
+}
+
+/// Xiph laces packets into one, as in Matroska.
+pub fn lace(packets: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = vec![packets.len() as u8 - 1];
+    for packet in &packets[..packets.len() - 1] {
+        let mut len = packet.len();
+        while len >= 255 {
+            data.push(255);
+            len -= 255;
+        }
+        data.push(len as u8);
+    }
+    for packet in packets {
+        data.extend_from_slice(packet);
+    }
+    data
+}
+
+/// Encodes standard base64 with padding.
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let mut v = 0;
+        for i in 0..3 {
+            v = v << 8 | u32::from(chunk.get(i).cloned().unwrap_or(0));
+        }
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(v >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Builds a FLAC picture block of a 2 by 1 front cover.
+pub fn picture_block(mime: &str, image: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let push_bytes = |data: &mut Vec<u8>, bytes: &[u8]| {
+        data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        data.extend_from_slice(bytes);
+    };
+    data.extend_from_slice(&3u32.to_be_bytes());
+    push_bytes(&mut data, mime.as_bytes());
+    push_bytes(&mut data, b"cover");
+    for &v in &[2u32, 1, 24, 0] {
+        data.extend_from_slice(&v.to_be_bytes());
+    }
+    push_bytes(&mut data, image);
+    data
+}
+
+/// Builds an audio packet, the window flags of a long block telling
+/// whether its neighbours are long blocks.
+pub fn audio_packet(long: bool, prev_long: bool, next_long: bool, amps: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.write(0, 1);
+    w.write(long as u32, 1);
+    if long {
+        w.write(prev_long as u32, 1);
+        w.write(next_long as u32, 1);
+    }
+    for &amp in amps {
+        if amp == 0 {
+            w.write(0, 1);
+        } else {
+            w.write(1, 1);
+            w.write(u32::from(amp), 8);
+            w.write(u32::from(amp), 8);
+        }
+    }
+    // The class, then the unit value, of every channel in use
+    for _ in amps.iter().filter(|&&amp| amp > 0) {
+        w.write(0, 1);
+    }
+    for _ in amps.iter().filter(|&&amp| amp > 0) {
+        w.write(1, 1);
+    }
+    w.into_bytes()
+}
+
+/// Builds the audio packets of a sequence of blocks, `true` for long ones.
+/// A long last block ends with a long window.
+pub fn audio_packets(blocks: &[bool], amps: &[u8]) -> Vec<Vec<u8>> {
+    (0..blocks.len())
+        .map(|i| {
+            let prev = i > 0 && blocks[i - 1];
+            let next = blocks.get(i + 1).cloned().unwrap_or(blocks[i]);
+            audio_packet(blocks[i], prev, next, amps)
+        })
+        .collect()
+}
+
+/// Returns the block size of a packet and the sizes of its left and right
+/// windows.
+fn windows(data: &[u8]) -> (usize, usize, usize) {
+    let flags = |bit: u32| data[0] >> bit & 1 == 1;
+    let len = |long: bool| if long { LONG } else { SHORT };
+    if flags(1) {
+        (LONG, len(flags(2)), len(flags(3)))
+    } else {
+        (SHORT, SHORT, SHORT)
+    }
+}
+
+/// Returns the number of samples per channel each packet decodes to, none
+/// for the first one.
+pub fn decoded_lengths(packets: &[Vec<u8>]) -> Vec<usize> {
+    let mut prev_right = None;
+    packets
+        .iter()
+        .map(|data| {
+            let (n, left, right) = windows(data);
+            let len = prev_right.map_or(0, |prev: usize| {
+                prev / 2 + (3 * n / 4 - right / 4) - (n / 4 + left / 4)
+            });
+            prev_right = Some(right);
+            len
+        })
+        .collect()
+}
+
+/// Returns the length of the tail `send_eof` drains after `last`.
+pub fn tail_len(last: &[u8]) -> usize {
+    windows(last).2 / 2
+}
+
+/// Returns the granule position after every packet.
+pub fn granules(packets: &[Vec<u8>]) -> Vec<u64> {
+    decoded_lengths(packets)
+        .iter()
+        .scan(0, |pos, &len| {
+            *pos += len as u64;
+            Some(*pos)
+        })
+        .collect()
+}
+
+/// Returns the extradata of a stream of `channels` channels.
+pub fn extradata(channels: u8, rate: u32) -> Vec<u8> {
+    lace_headers(&headers(channels, rate))
+}
+
+pub fn packet(data: &[u8]) -> Packet {
+    Packet {
+        data: data.to_vec(),
+        ..Default::default()
+    }
+}
+
+/// Returns a decoder configured for a stream of `channels` channels.
+pub fn decoder(channels: u8) -> Dec {
+    configure(Dec::new(), channels)
+}
+
+pub fn configure(mut dec: Dec, channels: u8) -> Dec {
+    dec.set_extradata(&extradata(channels, 48000));
+    dec.configure().unwrap();
+    dec
+}
+
+pub const BOS: u8 = 2;
+pub const EOS: u8 = 4;
+pub const CONTINUED: u8 = 1;
+
+/// Builds an Ogg page from the lacing values of its segments and its body.
+pub fn page(serial: u32, seq: u32, granule: i64, flags: u8, lacing: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut page = b"OggS".to_vec();
+    page.push(0);
+    page.push(flags);
+    page.extend_from_slice(&granule.to_le_bytes());
+    page.extend_from_slice(&serial.to_le_bytes());
+    page.extend_from_slice(&seq.to_le_bytes());
+    page.extend_from_slice(&[0; 4]);
+    page.push(lacing.len() as u8);
+    page.extend_from_slice(lacing);
+    page.extend_from_slice(body);
+    let crc = page_crc(&page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+/// Returns the lacing values of a packet ending on its page.
+pub fn lacing(len: usize) -> Vec<u8> {
+    let mut lacing = vec![255; len / 255];
+    lacing.push((len % 255) as u8);
+    lacing
+}
+
+/// Builds a logical stream with one packet per page, the audio pages
+/// carrying the given granule positions.
+pub fn ogg_stream(
+    serial: u32,
+    headers: &[Vec<u8>; 3],
+    audio: &[Vec<u8>],
+    granules: &[u64],
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    let count = 3 + audio.len();
+    for (i, data) in headers.iter().chain(audio).enumerate() {
+        let mut flags = if i == 0 { BOS } else { 0 };
+        if i + 1 == count {
+            flags |= EOS;
+        }
+        let granule = if i < 3 { 0 } else { granules[i - 3] as i64 };
+        out.extend(page(
+            serial,
+            i as u32,
+            granule,
+            flags,
+            &lacing(data.len()),
+            data,
+        ));
+    }
+    out
+}
@@ -1,5 +1,11 @@
-extern crate av_data as data;
 extern crate av_codec as codec;
+extern crate av_data as data;
 extern crate lewton;
 
+pub mod comment;
 pub mod decoder;
+#[cfg(test)]
+mod fixture;
+pub mod pcm;
+pub mod setup;
+pub mod stream;
@@ -0,0 +1,259 @@
+use codec::decoder::Decoder;
+use codec::error::Error;
+use data::frame::{Frame, MediaKind};
+use data::packet::Packet;
+use decoder::{samples_per_channel, Dec};
+use std::io;
+use std::io::Read;
+
+/// Byte order of serialized PCM samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Serializes the samples of an audio frame as interleaved little-endian
+/// PCM bytes.
+pub fn frame_to_le_bytes(frame: &Frame) -> Vec<u8> {
+    frame_to_bytes(frame, Endian::Little)
+}
+
+/// Serializes the samples of an audio frame as interleaved PCM bytes in
+/// the requested byte order.
+///
+/// Planar frames are interleaved, the alignment padding of the frame
+/// buffer is left out. Video frames produce no bytes.
+pub fn frame_to_bytes(frame: &Frame, endian: Endian) -> Vec<u8> {
+    let info = match frame.kind {
+        MediaKind::Audio(ref info) => info,
+        MediaKind::Video(_) => return Vec::new(),
+    };
+    let channels = info.map.len();
+    if channels == 0 {
+        return Vec::new();
+    }
+    let size = (info.format.bits as usize).div_ceil(8);
+    let samples = samples_per_channel(info);
+    let swap = info.format.be != (endian == Endian::Big);
+    let mut out = Vec::with_capacity(samples * channels * size);
+    let planes: Vec<&[u8]> = (0..frame.buf.count())
+        .filter_map(|i| frame.buf.as_slice_inner(i).ok())
+        .collect();
+    for i in 0..samples {
+        for c in 0..channels {
+            let (plane, idx) = if info.format.planar {
+                (planes[c], i)
+            } else {
+                (planes[0], i * channels + c)
+            };
+            let sample = &plane[idx * size..(idx + 1) * size];
+            if swap {
+                out.extend(sample.iter().rev());
+            } else {
+                out.extend_from_slice(sample);
+            }
+        }
+    }
+    out
+}
+
+/// Adapter exposing the decoded PCM of a packet source through
+/// `std::io::Read`.
+///
+/// Packets are pulled from the source and decoded only when the
+/// previously decoded frame has been read completely. Once the source is
+/// exhausted the stream is ended with `Dec::send_eof`, so the tail of a
+/// decoder created with `Dec::new` is read as well.
+pub struct VorbisPcmReader<I: Iterator<Item = Packet>> {
+    dec: Dec,
+    packets: I,
+    buf: Vec<u8>,
+    pos: usize,
+    endian: Endian,
+    ended: bool,
+}
+
+impl<I: Iterator<Item = Packet>> VorbisPcmReader<I> {
+    /// Wraps a decoder and the source of its packets, which may start with
+    /// the headers if `Dec::set_in_band_headers` is enabled.
+    ///
+    /// Packets that fail to decode make `read` fail with
+    /// `io::ErrorKind::InvalidData`, other decoder errors are reported as
+    /// `io::ErrorKind::Other`.
+    pub fn new(dec: Dec, packets: I) -> Self {
+        VorbisPcmReader {
+            dec,
+            packets,
+            buf: Vec::new(),
+            pos: 0,
+            endian: Endian::Little,
+            ended: false,
+        }
+    }
+
+    /// Selects the byte order of the samples, little-endian by default.
+    ///
+    /// Only affects frames decoded after the call.
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+
+    /// Returns the underlying decoder.
+    pub fn into_inner(self) -> Dec {
+        self.dec
+    }
+
+    /// Refills the buffer, returns `false` once the source is exhausted.
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            match self.dec.receive_frame() {
+                Ok(frame) => {
+                    self.buf = frame_to_bytes(&frame, self.endian);
+                    self.pos = 0;
+                    if !self.buf.is_empty() {
+                        return Ok(true);
+                    }
+                }
+                Err(Error::MoreDataNeeded) => {
+                    let ret = match self.packets.next() {
+                        Some(pkt) => self.dec.send_packet(&pkt),
+                        None if self.ended => return Ok(false),
+                        None => {
+                            self.ended = true;
+                            self.dec.send_eof()
+                        }
+                    };
+                    match ret {
+                        // Headers sent in band, no audio yet
+                        Ok(()) | Err(Error::MoreDataNeeded) => {}
+                        Err(Error::InvalidData) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                Error::InvalidData,
+                            ))
+                        }
+                        Err(e) => return Err(io::Error::other(e)),
+                    }
+                }
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = Packet>> Read for VorbisPcmReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.buf.len() && !self.fill()? {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture;
+
+    fn packets() -> Vec<Packet> {
+        fixture::audio_packets(&[true, false, false, true], &[255, 200])
+            .iter()
+            .map(|data| fixture::packet(data))
+            .collect()
+    }
+
+    fn direct_decode() -> Vec<u8> {
+        let mut dec = fixture::decoder(2);
+        let mut pcm = Vec::new();
+        for pkt in packets() {
+            dec.send_packet(&pkt).unwrap();
+            while let Ok(f) = dec.receive_frame() {
+                pcm.extend(frame_to_le_bytes(&f));
+            }
+        }
+        dec.send_eof().unwrap();
+        while let Ok(f) = dec.receive_frame() {
+            pcm.extend(frame_to_le_bytes(&f));
+        }
+        pcm
+    }
+
+    #[test]
+    fn reader_yields_the_decoded_pcm() {
+        let mut reader = VorbisPcmReader::new(fixture::decoder(2), packets().into_iter());
+        let mut pcm = Vec::new();
+        reader.read_to_end(&mut pcm).unwrap();
+        assert!(!pcm.is_empty());
+        assert_eq!(pcm, direct_decode());
+    }
+
+    #[test]
+    fn reader_accepts_in_band_headers() {
+        let mut dec = Dec::new();
+        dec.set_in_band_headers(true);
+        let mut source: Vec<Packet> = fixture::headers(2, 48000)
+            .iter()
+            .map(|data| fixture::packet(data))
+            .collect();
+        source.extend(packets());
+        let mut reader = VorbisPcmReader::new(dec, source.into_iter());
+        let mut pcm = Vec::new();
+        reader.read_to_end(&mut pcm).unwrap();
+        assert_eq!(pcm, direct_decode());
+
+        // Only undecodable packets are data errors
+        let source = vec![fixture::packet(&[1, 2])].into_iter();
+        let mut reader = VorbisPcmReader::new(fixture::decoder(2), source);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let source = packets().into_iter();
+        let mut reader = VorbisPcmReader::new(Dec::new(), source);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn reader_handles_short_reads() {
+        let mut reader = VorbisPcmReader::new(fixture::decoder(2), packets().into_iter());
+        let mut pcm = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                len => pcm.extend_from_slice(&buf[..len]),
+            }
+        }
+        assert_eq!(pcm, direct_decode());
+    }
+
+    #[test]
+    fn big_endian_reverses_every_sample() {
+        use decoder::OutputFormat;
+        for &(format, size) in &[(OutputFormat::S16, 2), (OutputFormat::S24, 4)] {
+            let mut dec = fixture::decoder(2);
+            dec.set_output_format(format);
+            for pkt in packets().iter().take(2) {
+                dec.send_packet(pkt).unwrap();
+            }
+            let frame = dec.receive_frame().unwrap();
+            let le = frame_to_bytes(&frame, Endian::Little);
+            let be = frame_to_bytes(&frame, Endian::Big);
+            assert_eq!(le, frame_to_le_bytes(&frame));
+            assert_eq!(le.len(), be.len());
+            assert!(le.iter().any(|&b| b != 0));
+            for (l, b) in le.chunks(size).zip(be.chunks(size)) {
+                let mut l = l.to_vec();
+                l.reverse();
+                assert_eq!(l, b);
+            }
+        }
+    }
+}
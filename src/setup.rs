@@ -0,0 +1,428 @@
+//! Lightweight walk over the Vorbis setup header.
+//!
+//! lewton keeps the parsed setup header opaque, so the few facts about it
+//! this crate needs are gathered by skipping through the packet again,
+//! following section 4.2.4 of the Vorbis I specification.
+
+use lewton::header::SetupHeader;
+
+/// Number of the configuration items declared in a setup header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SetupSummary {
+    pub codebooks: usize,
+    pub floors: usize,
+    pub residues: usize,
+    pub mappings: usize,
+    pub modes: usize,
+}
+
+/// What the decoder retains of the setup header.
+pub(crate) struct SetupInfo {
+    pub summary: SetupSummary,
+    /// The block flag of every mode, `true` for long blocks.
+    pub mode_blockflags: Vec<bool>,
+}
+
+/// A parsed setup header, as used by a configured `Dec`.
+///
+/// Tracks from the same encoder often carry identical setup headers, whose
+/// codebooks are costly to parse. Get the setup of a configured decoder
+/// with `Dec::parsed_setup` and hand it to the decoder of the next track
+/// with `Dec::install_setup` to reuse it.
+pub struct ParsedSetup {
+    pub(crate) hash: u64,
+    /// The setup header and the identification header fields it was
+    /// parsed with, compared on a hash match.
+    pub(crate) key: Vec<u8>,
+    pub(crate) header: SetupHeader,
+    pub(crate) info: Option<SetupInfo>,
+}
+
+impl ParsedSetup {
+    /// Returns the number of codebooks, floors, residues, mappings and
+    /// modes declared by the setup header, `None` if the crate could not
+    /// walk it.
+    pub fn summary(&self) -> Option<SetupSummary> {
+        self.info.as_ref().map(|info| info.summary)
+    }
+}
+
+/// Reader for the LSB-first bit packing used by Vorbis.
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    pos: u64,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    /// Reads up to 32 bits, `None` at the end of the data.
+    pub fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut v = 0u32;
+        for i in 0..bits {
+            let byte = *self.data.get((self.pos >> 3) as usize)?;
+            v |= u32::from((byte >> (self.pos & 7)) & 1) << i;
+            self.pos += 1;
+        }
+        Some(v)
+    }
+
+    pub fn skip(&mut self, bits: u64) -> Option<()> {
+        let pos = self.pos.checked_add(bits)?;
+        if pos > self.data.len() as u64 * 8 {
+            return None;
+        }
+        self.pos = pos;
+        Some(())
+    }
+}
+
+/// The `ilog` function of the specification, the position of the highest
+/// set bit.
+pub(crate) fn ilog(v: u32) -> u32 {
+    32 - v.leading_zeros()
+}
+
+/// The greatest `r` for which `r.pow(dims) <= entries`.
+fn lookup1_values(entries: u32, dims: u32) -> u64 {
+    if dims == 0 {
+        return 0;
+    }
+    let fits = |r: u64| match r.checked_pow(dims) {
+        Some(p) => p <= u64::from(entries),
+        None => false,
+    };
+    let mut r = f64::from(entries).powf(1.0 / f64::from(dims)).floor() as u64;
+    while fits(r + 1) {
+        r += 1;
+    }
+    while r > 0 && !fits(r) {
+        r -= 1;
+    }
+    r
+}
+
+fn skip_codebook(r: &mut BitReader) -> Option<()> {
+    if r.read(24)? != 0x56_43_42 {
+        return None;
+    }
+    let dims = r.read(16)?;
+    let entries = r.read(24)?;
+    if r.read(1)? == 0 {
+        let sparse = r.read(1)? == 1;
+        for _ in 0..entries {
+            if !sparse || r.read(1)? == 1 {
+                r.read(5)?;
+            }
+        }
+    } else {
+        r.read(5)?;
+        let mut entry = 0;
+        while entry < entries {
+            entry += r.read(ilog(entries - entry))?;
+        }
+        if entry > entries {
+            return None;
+        }
+    }
+    match r.read(4)? {
+        0 => {}
+        kind @ 1..=2 => {
+            r.skip(64)?;
+            let value_bits = r.read(4)? + 1;
+            r.read(1)?;
+            let values = if kind == 1 {
+                lookup1_values(entries, dims)
+            } else {
+                u64::from(entries) * u64::from(dims)
+            };
+            r.skip(values * u64::from(value_bits))?;
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn skip_floor(r: &mut BitReader) -> Option<()> {
+    match r.read(16)? {
+        0 => {
+            r.skip(8 + 16 + 16 + 6 + 8)?;
+            let books = r.read(4)? + 1;
+            r.skip(u64::from(books) * 8)?;
+        }
+        1 => {
+            let partitions = r.read(5)?;
+            let mut classes = Vec::with_capacity(partitions as usize);
+            for _ in 0..partitions {
+                classes.push(r.read(4)?);
+            }
+            let class_count = classes.iter().map(|&c| c as usize + 1).max().unwrap_or(0);
+            let mut dims = Vec::with_capacity(class_count);
+            for _ in 0..class_count {
+                dims.push(r.read(3)? + 1);
+                let subclasses = r.read(2)?;
+                if subclasses > 0 {
+                    r.read(8)?;
+                }
+                r.skip((1 << subclasses) * 8)?;
+            }
+            r.read(2)?;
+            let range_bits = r.read(4)?;
+            for c in classes {
+                r.skip(u64::from(dims[c as usize] * range_bits))?;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn skip_residue(r: &mut BitReader) -> Option<()> {
+    if r.read(16)? > 2 {
+        return None;
+    }
+    r.skip(24 * 3)?;
+    let classifications = r.read(6)? + 1;
+    r.read(8)?;
+    let mut cascades = Vec::with_capacity(classifications as usize);
+    for _ in 0..classifications {
+        let low = r.read(3)?;
+        let high = if r.read(1)? == 1 { r.read(5)? } else { 0 };
+        cascades.push(high << 3 | low);
+    }
+    for cascade in cascades {
+        r.skip(u64::from(cascade.count_ones()) * 8)?;
+    }
+    Some(())
+}
+
+const TRUNCATED: &str = "truncated setup header";
+const BAD_MAPPING: &str = "malformed setup header mapping";
+const CHANNEL_MISMATCH: &str = "channel count mismatch between ident and setup";
+
+fn skip_mapping(r: &mut BitReader, channels: u32) -> Result<(), &'static str> {
+    if r.read(16).ok_or(BAD_MAPPING)? != 0 {
+        return Err(BAD_MAPPING);
+    }
+    let submaps = if r.read(1).ok_or(BAD_MAPPING)? == 1 {
+        r.read(4).ok_or(BAD_MAPPING)? + 1
+    } else {
+        1
+    };
+    if r.read(1).ok_or(BAD_MAPPING)? == 1 {
+        let steps = r.read(8).ok_or(BAD_MAPPING)? + 1;
+        let bits = ilog(channels - 1);
+        for _ in 0..steps {
+            let magnitude = r.read(bits).ok_or(BAD_MAPPING)?;
+            let angle = r.read(bits).ok_or(BAD_MAPPING)?;
+            if magnitude == angle {
+                return Err(BAD_MAPPING);
+            }
+            // The coupling steps refer to channels the stream lacks
+            if magnitude >= channels || angle >= channels {
+                return Err(CHANNEL_MISMATCH);
+            }
+        }
+    }
+    if r.read(2).ok_or(BAD_MAPPING)? != 0 {
+        return Err(BAD_MAPPING);
+    }
+    if submaps > 1 {
+        for _ in 0..channels {
+            if r.read(4).ok_or(BAD_MAPPING)? >= submaps {
+                return Err(BAD_MAPPING);
+            }
+        }
+    }
+    r.skip(u64::from(submaps) * 24).ok_or(BAD_MAPPING)
+}
+
+/// Walks a complete setup header packet of a stream with `channels`
+/// channels, describing what is malformed on failure.
+pub(crate) fn parse_setup(packet: &[u8], channels: u8) -> Result<SetupInfo, &'static str> {
+    if packet.len() < 7 || packet[0] != 5 || &packet[1..7] != b"vorbis" {
+        return Err("bad setup header magic");
+    }
+    if channels == 0 {
+        return Err(CHANNEL_MISMATCH);
+    }
+    let channels = u32::from(channels);
+    let mut r = BitReader::new(&packet[7..]);
+
+    let codebooks = r.read(8).ok_or(TRUNCATED)? as usize + 1;
+    for _ in 0..codebooks {
+        skip_codebook(&mut r).ok_or("malformed setup header codebook")?;
+    }
+    let times = r.read(6).ok_or(TRUNCATED)? + 1;
+    for _ in 0..times {
+        if r.read(16).ok_or(TRUNCATED)? != 0 {
+            return Err("nonzero setup header time domain transform");
+        }
+    }
+    let floors = r.read(6).ok_or(TRUNCATED)? as usize + 1;
+    for _ in 0..floors {
+        skip_floor(&mut r).ok_or("malformed setup header floor")?;
+    }
+    let residues = r.read(6).ok_or(TRUNCATED)? as usize + 1;
+    for _ in 0..residues {
+        skip_residue(&mut r).ok_or("malformed setup header residue")?;
+    }
+    let mappings = r.read(6).ok_or(TRUNCATED)? as usize + 1;
+    for _ in 0..mappings {
+        skip_mapping(&mut r, channels)?;
+    }
+    let modes = r.read(6).ok_or(TRUNCATED)? as usize + 1;
+    let mut mode_blockflags = Vec::with_capacity(modes);
+    for _ in 0..modes {
+        mode_blockflags.push(r.read(1).ok_or(TRUNCATED)? == 1);
+        r.skip(16 + 16 + 8).ok_or(TRUNCATED)?;
+    }
+    if r.read(1).ok_or(TRUNCATED)? != 1 {
+        return Err("bad setup header framing bit");
+    }
+
+    Ok(SetupInfo {
+        summary: SetupSummary {
+            codebooks,
+            floors,
+            residues,
+            mappings,
+            modes,
+        },
+        mode_blockflags,
+    })
+}
+
+/// Reads the packet type and the mode number at the start of an audio
+/// packet.
+fn packet_mode(setup: &SetupInfo, r: &mut BitReader) -> Option<u32> {
+    if r.read(1)? != 0 {
+        return None;
+    }
+    let mode_bits = ilog(setup.summary.modes as u32 - 1);
+    let mode = r.read(mode_bits)?;
+    if mode as usize >= setup.mode_blockflags.len() {
+        return None;
+    }
+    Some(mode)
+}
+
+/// Returns whether the audio packet starting with `head` is a long block,
+/// `None` if it is not an audio packet.
+pub(crate) fn is_long_block(setup: &SetupInfo, head: [u8; 2]) -> Option<bool> {
+    let mode = packet_mode(setup, &mut BitReader::new(&head))?;
+    Some(setup.mode_blockflags[mode as usize])
+}
+
+/// Returns whether the audio packet starting with `head` is a long block,
+/// and whether its left and right windows are long, `None` if it is not an
+/// audio packet.
+pub(crate) fn block_windows(setup: &SetupInfo, head: [u8; 2]) -> Option<(bool, bool, bool)> {
+    let mut r = BitReader::new(&head);
+    let mode = packet_mode(setup, &mut r)?;
+    if !setup.mode_blockflags[mode as usize] {
+        return Some((false, false, false));
+    }
+    let left = r.read(1)? == 1;
+    let right = r.read(1)? == 1;
+    Some((true, left, right))
+}
+
+/// Builds an audio packet in which every channel is unused, to follow the
+/// packet starting with `head`.
+///
+/// Decoding it yields exactly the remaining overlap of the previous window
+/// faded against silence, i.e. the tail of the stream.
+pub(crate) fn silent_packet(setup: &SetupInfo, head: [u8; 2], channels: u8) -> Option<Vec<u8>> {
+    let mode_bits = ilog(setup.summary.modes as u32 - 1);
+    let mut r = BitReader::new(&head);
+    let mode = packet_mode(setup, &mut r)?;
+    let long = setup.mode_blockflags[mode as usize];
+    let bits = if long {
+        r.read(1)?;
+        if r.read(1)? == 1 {
+            // The overlap is half a long block: a long block with both
+            // windows long returns exactly that much
+            mode << 1 | 0b11 << (1 + mode_bits)
+        } else {
+            // The overlap is half a short block, as is a short block
+            let short = setup.mode_blockflags.iter().position(|&long| !long)?;
+            (short as u32) << 1
+        }
+    } else {
+        mode << 1
+    };
+    // A zero amplitude or nonzero flag marks an unused floor, for floor 0
+    // the amplitude takes up to 63 bits
+    let mut pkt = vec![0; 2 + 8 * channels as usize];
+    pkt[..2].copy_from_slice(&(bits as u16).to_le_bytes());
+    Some(pkt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture;
+
+    #[test]
+    fn summary_counts_the_setup_items() {
+        let info = parse_setup(&fixture::setup(), 2).unwrap();
+        let summary = SetupSummary {
+            codebooks: 2,
+            floors: 1,
+            residues: 1,
+            mappings: 1,
+            modes: 2,
+        };
+        assert_eq!(info.summary, summary);
+        assert_eq!(info.mode_blockflags, [false, true]);
+        assert_eq!(fixture::decoder(2).setup_summary(), Some(summary));
+    }
+
+    #[test]
+    fn truncated_setup_is_rejected() {
+        let setup = fixture::setup();
+        for len in 0..setup.len() {
+            assert!(parse_setup(&setup[..len], 2).is_err(), "length {}", len);
+        }
+        assert_eq!(parse_setup(&setup, 0).err(), Some(CHANNEL_MISMATCH));
+        let mut bad = setup.clone();
+        bad[1] = b'V';
+        assert_eq!(parse_setup(&bad, 2).err(), Some("bad setup header magic"));
+    }
+
+    #[test]
+    fn lookup1_values_is_the_integer_root() {
+        assert_eq!(lookup1_values(2, 1), 2);
+        assert_eq!(lookup1_values(100, 2), 10);
+        assert_eq!(lookup1_values(99, 2), 9);
+        assert_eq!(lookup1_values(1000, 3), 10);
+        assert_eq!(lookup1_values(999, 3), 9);
+        assert_eq!(lookup1_values(1, 8), 1);
+    }
+
+    fn head(packet: &[u8]) -> [u8; 2] {
+        [packet[0], packet.get(1).cloned().unwrap_or(0)]
+    }
+
+    #[test]
+    fn silent_packet_completes_the_last_window() {
+        let info = parse_setup(&fixture::setup(), 2).unwrap();
+        let long_long = fixture::audio_packet(true, true, true, &[255, 200]);
+        let long_short = fixture::audio_packet(true, true, false, &[255, 200]);
+        let short = fixture::audio_packet(false, false, false, &[255, 200]);
+        let silent = silent_packet(&info, head(&long_long), 2).unwrap();
+        assert_eq!(silent.len(), 2 + 16);
+        assert_eq!(is_long_block(&info, head(&silent)), Some(true));
+        // Both window flags are set
+        assert_eq!(silent[0] & 0b1110, 0b1110);
+        for last in &[long_short, short] {
+            let silent = silent_packet(&info, head(last), 2).unwrap();
+            assert_eq!(is_long_block(&info, head(&silent)), Some(false));
+        }
+        assert!(silent_packet(&info, [1, 0], 2).is_none());
+        assert!(silent[2..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,569 @@
+//! Decoding of an Ogg Vorbis byte stream fed in arbitrary chunks.
+
+use codec::decoder::Decoder;
+use codec::error::*;
+use data::frame::{ArcFrame, AudioInfo};
+use data::packet::Packet;
+use decoder::Dec;
+use pcm::frame_to_le_bytes;
+use std::collections::VecDeque;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// The CRC-32 variant of Ogg: polynomial 0x04c11db7, not reflected, no
+/// initial or final xor.
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            r = if r & 0x8000_0000 != 0 {
+                (r << 1) ^ 0x04c1_1db7
+            } else {
+                r << 1
+            };
+            bit += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+}
+
+fn update_crc(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+/// Computes the checksum of a page, over the page with the checksum field
+/// set to zero.
+pub fn page_crc(page: &[u8]) -> u32 {
+    let crc = update_crc(0, &page[..22]);
+    let crc = update_crc(crc, &[0; 4]);
+    update_crc(crc, &page[26..])
+}
+
+/// A packet extracted from the pages, with the granule position of the
+/// page if it is the last packet completed on it.
+struct StreamPacket {
+    data: Vec<u8>,
+    granule: Option<u64>,
+    eos: bool,
+}
+
+/// Push based decoder for Ogg Vorbis streams.
+///
+/// The bytes can come in chunks of any size, e.g. as read from an async
+/// source: `push_bytes` only buffers them and splits off the complete
+/// packets, which `pull_frame` decodes synchronously. Pages with a bad
+/// checksum are skipped, and only the first Vorbis logical stream, or the
+/// streams chained after it, is decoded.
+pub struct VorbisStreamDecoder {
+    dec: Dec,
+    buf: Vec<u8>,
+    /// Start of the bytes of `buf` not parsed yet.
+    pos: usize,
+    partial: Option<Vec<u8>>,
+    serial: Option<u32>,
+    /// Sequence number the next page of the stream should have.
+    next_seq: Option<u32>,
+    ended: bool,
+    /// The last packet of a stream was sent, the decoder still has to be
+    /// told.
+    eos_pending: bool,
+    packets: VecDeque<StreamPacket>,
+}
+
+impl VorbisStreamDecoder {
+    /// Wraps a decoder, which is configured from the headers carried by
+    /// the stream.
+    ///
+    /// The granule positions of the pages end the stream where the encoder
+    /// did, whether the decoder was created with `Dec::new`, which trims
+    /// the tail of the last block to the final granule position, or with
+    /// `Dec::new_streaming`.
+    pub fn new(mut dec: Dec) -> Self {
+        dec.set_in_band_headers(true);
+        VorbisStreamDecoder {
+            dec,
+            buf: Vec::new(),
+            pos: 0,
+            partial: None,
+            serial: None,
+            next_seq: None,
+            ended: false,
+            eos_pending: false,
+            packets: VecDeque::new(),
+        }
+    }
+
+    /// Gives access to the decoder, e.g. for its headers or options.
+    pub fn decoder(&mut self) -> &mut Dec {
+        &mut self.dec
+    }
+
+    /// Buffers the next bytes of the stream.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        while self.read_page() {}
+        // Only the start of an incomplete page is left, moving it once per
+        // call rather than once per page keeps the parsing linear
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+    }
+
+    /// Returns the next decoded frame, or `Error::MoreDataNeeded` if more
+    /// bytes have to be pushed first.
+    ///
+    /// The frames are timed from the decoded sample position as by
+    /// `Dec::sample_to_timestamp`. A packet that fails to decode is dropped
+    /// after its error has been returned, so the next call continues with
+    /// the following one. The end of a stream is signalled to the decoder
+    /// even if its last packet fails.
+    pub fn pull_frame(&mut self) -> Result<ArcFrame> {
+        loop {
+            match self.dec.receive_frame() {
+                Err(Error::MoreDataNeeded) => {}
+                ret => return ret,
+            }
+            if self.eos_pending {
+                self.eos_pending = false;
+                self.dec.send_eof()?;
+                continue;
+            }
+            let packet = self.packets.pop_front().ok_or(Error::MoreDataNeeded)?;
+            let pkt = Packet {
+                data: packet.data,
+                t: self
+                    .dec
+                    .sample_to_timestamp(self.dec.samples_decoded())
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            let ret = match packet.granule {
+                Some(granule) if packet.eos => {
+                    self.dec.send_last_packet_with_granule(&pkt, granule)
+                }
+                Some(granule) => self.dec.send_packet_with_granule(&pkt, granule),
+                None => self.dec.send_packet(&pkt),
+            };
+            // A gapless decoder overlaps the stream with the chained one
+            self.eos_pending = packet.eos && !self.dec.gapless();
+            match ret {
+                // Audio ahead of the headers, not decodable
+                Err(Error::MoreDataNeeded) => {}
+                ret => ret?,
+            }
+        }
+    }
+
+    /// Reads the page at the parse position, returns `false` if it is
+    /// incomplete.
+    fn read_page(&mut self) -> bool {
+        match self.buf[self.pos..].windows(4).position(|w| w == b"OggS") {
+            Some(start) => self.pos += start,
+            None => {
+                // Keep what could be the start of a capture pattern
+                self.pos = self.buf.len() - (self.buf.len() - self.pos).min(3);
+                return false;
+            }
+        }
+        let data = &self.buf[self.pos..];
+        if data.len() < 27 {
+            return false;
+        }
+        if data[4] != 0 {
+            // Not a page after all, rather than waiting for its length
+            self.pos += 4;
+            return true;
+        }
+        let segments = data[26] as usize;
+        if data.len() < 27 + segments {
+            return false;
+        }
+        let body_len: usize = data[27..27 + segments].iter().map(|&l| l as usize).sum();
+        let page_len = 27 + segments + body_len;
+        if data.len() < page_len {
+            return false;
+        }
+        let page = &data[..page_len];
+        let stored_crc = u32::from_le_bytes([page[22], page[23], page[24], page[25]]);
+        if page_crc(page) != stored_crc {
+            // Damaged, resync after the capture pattern
+            self.pos += 4;
+            return true;
+        }
+        let buf = std::mem::take(&mut self.buf);
+        self.read_packets(&buf[self.pos..self.pos + page_len], segments);
+        self.buf = buf;
+        self.pos += page_len;
+        true
+    }
+
+    fn read_packets(&mut self, page: &[u8], segments: usize) {
+        let flags = page[5];
+        let (continued, bos, eos) = (flags & 1 != 0, flags & 2 != 0, flags & 4 != 0);
+        let mut granule = [0; 8];
+        granule.copy_from_slice(&page[6..14]);
+        let granule = i64::from_le_bytes(granule);
+        let serial = u32::from_le_bytes([page[14], page[15], page[16], page[17]]);
+        let lacing = &page[27..27 + segments];
+        let body = &page[27 + segments..];
+
+        if self.serial != Some(serial) {
+            let ident = bos && body.len() >= 7 && body[0] == 1 && &body[1..7] == b"vorbis";
+            // Follow a chained stream, ignore the other multiplexed ones
+            if !ident || (self.serial.is_some() && !self.ended) {
+                return;
+            }
+            self.serial = Some(serial);
+            self.next_seq = None;
+            self.partial = None;
+        }
+        self.ended = eos;
+        let seq = u32::from_le_bytes([page[18], page[19], page[20], page[21]]);
+        // A packet continued across a lost page misses its middle
+        let gap = self.next_seq.is_some_and(|next| next != seq);
+        self.next_seq = Some(seq.wrapping_add(1));
+        if !continued || gap {
+            self.partial = None;
+        }
+
+        let mut completed = Vec::new();
+        let mut pos = 0;
+        // Continuation data without its start is useless
+        let mut skip = continued && self.partial.is_none();
+        for &len in lacing {
+            let segment = &body[pos..pos + len as usize];
+            pos += len as usize;
+            if !skip {
+                self.partial
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(segment);
+            }
+            if len < 255 {
+                if !skip {
+                    completed.extend(self.partial.take());
+                }
+                skip = false;
+            }
+        }
+        let count = completed.len();
+        for (i, data) in completed.into_iter().enumerate() {
+            let last = i + 1 == count;
+            self.packets.push_back(StreamPacket {
+                data,
+                granule: if last && granule >= 0 {
+                    Some(granule as u64)
+                } else {
+                    None
+                },
+                eos: last && eos,
+            });
+        }
+    }
+}
+
+/// Decodes a complete Ogg Vorbis file held in memory to interleaved 16-bit
+/// samples.
+///
+/// Returns the audio parameters of the stream, with `samples` holding the
+/// number of samples of all channels together as for a frame, and the
+/// samples. Chained streams are decoded back to back as long as they keep
+/// the sample rate and channels of the first one, the samples could not be
+/// told apart otherwise: a change fails with `Error::Unsupported`. Fails
+/// with `Error::InvalidData` if the bytes hold no Vorbis stream, and like
+/// `Dec::send_packet` on undecodable packets.
+pub fn decode_ogg_vorbis(bytes: &[u8]) -> Result<(AudioInfo, Vec<i16>)> {
+    let mut stream = VorbisStreamDecoder::new(Dec::new_streaming());
+    stream.push_bytes(bytes);
+    let mut pcm = Vec::new();
+    loop {
+        let ret = stream.pull_frame();
+        if stream.decoder().took_new_config() {
+            return Err(Error::Unsupported(PARAMETERS_CHANGED.to_owned()));
+        }
+        match ret {
+            Ok(frame) => pcm.extend(
+                frame_to_le_bytes(&frame)
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]])),
+            ),
+            Err(Error::MoreDataNeeded) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if stream.decoder().ident_header().is_none() {
+        return Err(Error::InvalidData);
+    }
+    let mut info = stream.decoder().audio_info().clone();
+    info.samples = pcm.len();
+    Ok((info, pcm))
+}
+
+const PARAMETERS_CHANGED: &str = "a chained stream changes the audio parameters";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(debug_assertions)]
+    use decoder::validate_frame;
+    use fixture;
+
+    #[test]
+    fn crc_is_the_ogg_variant() {
+        assert_eq!(update_crc(0, b"123456789"), 0x89a1_897f);
+        assert_eq!(update_crc(0, b""), 0);
+    }
+
+    /// Pushes the stream in chunks of `chunk` bytes and returns the
+    /// samples.
+    fn decode_chunked(bytes: &[u8], chunk: usize) -> Vec<u8> {
+        let mut stream = VorbisStreamDecoder::new(Dec::new());
+        let mut pcm = Vec::new();
+        for chunk in bytes.chunks(chunk) {
+            stream.push_bytes(chunk);
+            loop {
+                match stream.pull_frame() {
+                    Ok(frame) => pcm.extend(frame_to_le_bytes(&frame)),
+                    Err(Error::MoreDataNeeded) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        }
+        pcm
+    }
+
+    /// Decodes the packets directly, ending at the granule positions.
+    fn decode_direct(packets: &[Vec<u8>], granules: &[u64]) -> Vec<u8> {
+        let mut dec = fixture::decoder(2);
+        let mut pcm = Vec::new();
+        for (data, &granule) in packets.iter().zip(granules) {
+            dec.send_packet_with_granule(&fixture::packet(data), granule)
+                .unwrap();
+            while let Ok(frame) = dec.receive_frame() {
+                pcm.extend(frame_to_le_bytes(&frame));
+            }
+        }
+        dec.send_eof().unwrap();
+        while let Ok(frame) = dec.receive_frame() {
+            pcm.extend(frame_to_le_bytes(&frame));
+        }
+        pcm
+    }
+
+    #[test]
+    fn chunk_boundaries_do_not_matter() {
+        let packets = fixture::audio_packets(&[true, false, false, true, true, false], &[255, 200]);
+        let mut granules = fixture::granules(&packets);
+        // End the stream within the last block
+        *granules.last_mut().unwrap() -= 50;
+        let headers = fixture::headers(2, 48000);
+        let mut bytes = b"junk OggS".to_vec();
+        bytes.extend(fixture::ogg_stream(7, &headers, &packets, &granules));
+        let expected = decode_direct(&packets, &granules);
+        assert!(!expected.is_empty());
+        for &chunk in &[1, 2, 3, 5, 26, 27, 28, 100, 255, bytes.len()] {
+            assert_eq!(decode_chunked(&bytes, chunk), expected, "chunk {}", chunk);
+        }
+    }
+
+    #[test]
+    fn packets_span_pages_and_damaged_pages_are_skipped() {
+        let mut packets = fixture::audio_packets(&[true, false, true, true], &[255, 200]);
+        // Trailing bytes are ignored by the decoder, and let the packet
+        // continue on the next page
+        packets[1].resize(300, 0);
+        let granules = fixture::granules(&packets);
+        let mut bytes = Vec::new();
+        for (i, header) in fixture::headers(2, 48000).iter().enumerate() {
+            let flags = if i == 0 { fixture::BOS } else { 0 };
+            bytes.extend(fixture::page(
+                7,
+                i as u32,
+                0,
+                flags,
+                &fixture::lacing(header.len()),
+                header,
+            ));
+        }
+        let mut lacing = fixture::lacing(packets[0].len());
+        lacing.push(255);
+        let mut body = packets[0].clone();
+        body.extend_from_slice(&packets[1][..255]);
+        bytes.extend(fixture::page(7, 3, granules[0] as i64, 0, &lacing, &body));
+
+        let mut damaged = fixture::page(7, 4, -1, fixture::CONTINUED, &[10], &[1; 10]);
+        damaged[30] ^= 1;
+        bytes.extend(damaged);
+        bytes.extend(fixture::page(8, 0, 0, 0, &[3], b"abc"));
+
+        let mut lacing = vec![45];
+        lacing.extend(fixture::lacing(packets[2].len()));
+        let mut body = packets[1][255..].to_vec();
+        body.extend_from_slice(&packets[2]);
+        let flags = fixture::CONTINUED;
+        bytes.extend(fixture::page(
+            7,
+            4,
+            granules[2] as i64,
+            flags,
+            &lacing,
+            &body,
+        ));
+        let lacing = fixture::lacing(packets[3].len());
+        let flags = fixture::EOS;
+        bytes.extend(fixture::page(
+            7,
+            5,
+            granules[3] as i64,
+            flags,
+            &lacing,
+            &packets[3],
+        ));
+
+        let expected = decode_direct(&packets, &granules);
+        for &chunk in &[1, 7, 300, bytes.len()] {
+            assert_eq!(decode_chunked(&bytes, chunk), expected, "chunk {}", chunk);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn frames_pass_validation() {
+        let packets = fixture::audio_packets(&[true, false, true, true], &[255, 200]);
+        let granules = fixture::granules(&packets);
+        let bytes = fixture::ogg_stream(7, &fixture::headers(2, 48000), &packets, &granules);
+        let mut stream = VorbisStreamDecoder::new(Dec::new());
+        stream.push_bytes(&bytes);
+        let mut pos = 0;
+        while let Ok(frame) = stream.pull_frame() {
+            let info = stream.decoder().audio_info().clone();
+            let mut expected = info.clone();
+            expected.samples = frame_to_le_bytes(&frame).len() / 2;
+            validate_frame(&frame, &expected).unwrap();
+            pos += (expected.samples / 2) as i64;
+        }
+        assert_eq!(pos as u64, *granules.last().unwrap());
+    }
+
+    #[test]
+    fn one_shot_decode_returns_every_sample() {
+        let packets = fixture::audio_packets(&[true, false, true, true], &[255, 200]);
+        let granules = fixture::granules(&packets);
+        let headers = fixture::headers(2, 48000);
+        let bytes = fixture::ogg_stream(7, &headers, &packets, &granules);
+        let (info, pcm) = decode_ogg_vorbis(&bytes).unwrap();
+        assert_eq!(info.sample_rate, 48000);
+        assert_eq!(info.map.len(), 2);
+        assert_eq!(info.samples, pcm.len());
+        assert_eq!(pcm.len() as u64, 2 * granules[3]);
+        assert!(pcm.iter().any(|&s| s != 0));
+
+        // A chained stream with the same parameters is appended
+        let mut chained = bytes.clone();
+        chained.extend(fixture::ogg_stream(8, &headers, &packets, &granules));
+        let (_, twice) = decode_ogg_vorbis(&chained).unwrap();
+        assert_eq!(twice.len(), 2 * pcm.len());
+
+        // One with another sample rate is not
+        let mut chained = bytes.clone();
+        let headers = fixture::headers(2, 44100);
+        chained.extend(fixture::ogg_stream(8, &headers, &packets, &granules));
+        assert!(matches!(
+            decode_ogg_vorbis(&chained),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            decode_ogg_vorbis(b"junk"),
+            Err(Error::InvalidData)
+        ));
+    }
+
+    #[test]
+    fn packets_across_a_lost_page_are_dropped() {
+        let mut packets = fixture::audio_packets(&[true, false, true, true], &[255, 200]);
+        // Spans three pages, of which the middle one is lost
+        packets[1].resize(600, 0);
+        let granules = fixture::granules(&packets);
+        let mut bytes = Vec::new();
+        for (i, header) in fixture::headers(2, 48000).iter().enumerate() {
+            let flags = if i == 0 { fixture::BOS } else { 0 };
+            let lacing = fixture::lacing(header.len());
+            bytes.extend(fixture::page(7, i as u32, 0, flags, &lacing, header));
+        }
+        let mut lacing = fixture::lacing(packets[0].len());
+        lacing.push(255);
+        let mut body = packets[0].clone();
+        body.extend_from_slice(&packets[1][..255]);
+        bytes.extend(fixture::page(7, 3, granules[0] as i64, 0, &lacing, &body));
+        let mut lacing = vec![90];
+        lacing.extend(fixture::lacing(packets[2].len()));
+        let mut body = packets[1][510..].to_vec();
+        body.extend_from_slice(&packets[2]);
+        let flags = fixture::CONTINUED;
+        let granule = granules[2] as i64;
+        bytes.extend(fixture::page(7, 5, granule, flags, &lacing, &body));
+        let lacing = fixture::lacing(packets[3].len());
+        let granule = granules[3] as i64;
+        bytes.extend(fixture::page(
+            7,
+            6,
+            granule,
+            fixture::EOS,
+            &lacing,
+            &packets[3],
+        ));
+
+        let kept = [packets[0].clone(), packets[2].clone(), packets[3].clone()];
+        let expected = decode_direct(&kept, &[granules[0], granules[2], granules[3]]);
+        assert_ne!(expected, decode_direct(&packets, &granules));
+        assert_eq!(decode_chunked(&bytes, bytes.len()), expected);
+    }
+
+    #[test]
+    fn failed_last_packet_still_ends_the_stream() {
+        let mut packets = fixture::audio_packets(&[true, false, true], &[255, 200]);
+        let expected = decode_direct(&packets, &[u64::MAX; 3]);
+        packets.push(vec![1, 2]);
+        // No granule positions, so the whole tail is drained
+        let granules = [u64::MAX; 4];
+        let bytes = fixture::ogg_stream(7, &fixture::headers(2, 48000), &packets, &granules);
+        let mut stream = VorbisStreamDecoder::new(Dec::new());
+        stream.push_bytes(&bytes);
+        let (mut pcm, mut errors, mut pos) = (Vec::new(), 0, 0);
+        loop {
+            match stream.pull_frame() {
+                Ok(frame) => {
+                    assert_eq!(frame.t.pts, Some(pos));
+                    let bytes = frame_to_le_bytes(&frame);
+                    pos += (bytes.len() / 4) as i64;
+                    pcm.extend(bytes);
+                }
+                Err(Error::MoreDataNeeded) => break,
+                Err(_) => errors += 1,
+            }
+        }
+        assert_eq!(errors, 1);
+        assert_eq!(pcm, expected);
+    }
+
+    #[test]
+    fn empty_packets_within_the_stream_are_skipped() {
+        let packets = fixture::audio_packets(&[true, false, true, true], &[255, 200]);
+        let granules = fixture::granules(&packets);
+        let expected = decode_direct(&packets, &granules);
+        let mut with_empty = packets.clone();
+        with_empty.insert(2, Vec::new());
+        let mut empty_granules = granules.clone();
+        empty_granules.insert(2, granules[1]);
+        let headers = fixture::headers(2, 48000);
+        let bytes = fixture::ogg_stream(7, &headers, &with_empty, &empty_granules);
+        assert_eq!(decode_chunked(&bytes, bytes.len()), expected);
+        let (_, pcm) = decode_ogg_vorbis(&bytes).unwrap();
+        assert_eq!(pcm.len() as u64, 2 * granules[3]);
+    }
+}